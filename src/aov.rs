@@ -0,0 +1,146 @@
+/// An arbitrary output variable, an extra image written next to the color for compositing and
+/// denoising
+///
+/// Everything but the lighting passes describes the first surface hit by camera rays. Misses
+/// leave the values at zero and the IDs at -1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aov {
+    /// The base color of the material, including its texture
+    Albedo,
+    /// The world space shading normal, facing the camera
+    Normal,
+    /// The distance along the view direction of the camera
+    Depth,
+    /// The world space position
+    Position,
+    /// The index of the TLAS instance, unique for every mesh object until the scene changes
+    InstanceId,
+    /// The index of the material in the uploaded material buffer
+    MaterialId,
+    /// Light reflected diffusely by the first hit that arrived straight from a light
+    DirectDiffuse,
+    /// Light reflected diffusely by the first hit after bouncing off other surfaces
+    IndirectDiffuse,
+    /// Light emitted by the first hit
+    Emission,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 9] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::InstanceId,
+        Aov::MaterialId,
+        Aov::DirectDiffuse,
+        Aov::IndirectDiffuse,
+        Aov::Emission,
+    ];
+
+    /// Matches the `AOV_*` constants of `rt_compute.wgsl`
+    pub fn index(self) -> u32 {
+        self as u32
+    }
+
+    /// The layer name in OpenEXR files
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::InstanceId => "instance_id",
+            Aov::MaterialId => "material_id",
+            Aov::DirectDiffuse => "diffuse_direct",
+            Aov::IndirectDiffuse => "diffuse_indirect",
+            Aov::Emission => "emission",
+        }
+    }
+
+    /// The channels in the components of the AOV's pixels
+    pub fn channels(self) -> &'static [&'static str] {
+        match self {
+            Aov::Albedo | Aov::DirectDiffuse | Aov::IndirectDiffuse | Aov::Emission => {
+                &["R", "G", "B"]
+            }
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::InstanceId | Aov::MaterialId => &["id"],
+        }
+    }
+
+    /// Lighting passes are tone mapped like the color when they are viewed, everything else is
+    /// shown as it is
+    pub fn is_radiance(self) -> bool {
+        matches!(
+            self,
+            Aov::DirectDiffuse | Aov::IndirectDiffuse | Aov::Emission
+        )
+    }
+
+    /// Positions, depths and IDs need more precision than half floats have
+    pub fn full_precision(self) -> bool {
+        matches!(
+            self,
+            Aov::Depth | Aov::Position | Aov::InstanceId | Aov::MaterialId
+        )
+    }
+}
+
+/// A set of AOVs, every AOV in it costs memory and bandwidth while rendering
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AovSet {
+    bits: u32,
+}
+
+impl AovSet {
+    pub const EMPTY: AovSet = AovSet { bits: 0 };
+    pub const ALL: AovSet = AovSet {
+        bits: (1 << Aov::ALL.len()) - 1,
+    };
+
+    /// Returns the set with `aov` added
+    pub fn with(self, aov: Aov) -> AovSet {
+        AovSet {
+            bits: self.bits | 1 << aov.index(),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn without(self, aov: Aov) -> AovSet {
+        AovSet {
+            bits: self.bits & !(1 << aov.index()),
+        }
+    }
+
+    /// Returns the AOVs in either set
+    pub fn union(self, other: AovSet) -> AovSet {
+        AovSet {
+            bits: self.bits | other.bits,
+        }
+    }
+
+    pub fn contains(self, aov: Aov) -> bool {
+        self.bits & 1 << aov.index() != 0
+    }
+
+    pub fn len(self) -> usize {
+        self.bits.count_ones() as usize
+    }
+
+    #[allow(unused)]
+    pub fn is_empty(self) -> bool {
+        self.bits == 0
+    }
+
+    /// Iterates in the order of `Aov::ALL`, which is also the order of the AOVs in memory
+    pub fn iter(self) -> impl Iterator<Item = Aov> {
+        Aov::ALL.into_iter().filter(move |aov| self.contains(*aov))
+    }
+
+    /// The mask of `rt_compute.wgsl`, bit `aov.index()` is set for every AOV in the set
+    pub fn bits(self) -> u32 {
+        self.bits
+    }
+}
@@ -1,20 +1,108 @@
+use glam::Mat4;
+
 use crate::transform::Transform;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Camera {
+    /// How the scene is projected onto the image
+    pub projection: Projection,
+    /// The vertical field of view in degrees for perspective projections
     pub fov: f32,
     pub near_clip: f32,
     pub far_clip: f32,
+    /// The radius of the lens, `0.0` is a pinhole camera where everything is in focus. Only
+    /// perspective projections have depth of field.
+    pub aperture_radius: f32,
+    /// The distance from the camera to the plane that is in focus
+    pub focus_distance: f32,
+    /// The shape of the aperture, which out of focus highlights take on
+    pub bokeh: Bokeh,
+    /// The position and orientation of the camera in world space, it looks down its local -Z axis
+    ///
+    /// This maps camera space to world space, it's the inverse of a view matrix. Use
+    /// `view_matrix()` for the world to camera transform.
     pub transform: Transform,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// Uses the field of view of the camera
+    Perspective,
+    /// Parallel rays, `view_height` is the height of the visible area in world units
+    #[allow(unused)]
+    Orthographic { view_height: f32 },
+    /// A 360° panorama with the view direction in the center, the image should be twice as wide
+    /// as it's high
+    #[allow(unused)]
+    Equirectangular,
+    /// An equidistant fisheye whose image circle spans `fov` degrees across the image height
+    #[allow(unused)]
+    Fisheye { fov: f32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bokeh {
+    /// A perfectly round aperture
+    Circle,
+    /// A regular polygon formed by `count` aperture blades, turned by `rotation` radians
+    #[allow(unused)]
+    Blades { count: u32, rotation: f32 },
+}
+
 impl Default for Camera {
     fn default() -> Self {
         Self {
+            projection: Projection::Perspective,
             fov: 90.0,
             near_clip: 0.1,
             far_clip: 1000.0,
+            aperture_radius: 0.0,
+            focus_distance: 1.0,
+            bokeh: Bokeh::Circle,
             transform: Transform::default(),
         }
     }
 }
+
+impl Camera {
+    /// Returns the world to camera transform, the inverse of `transform`
+    pub fn view_matrix(&self) -> Mat4 {
+        Mat4::from(self.transform).inverse()
+    }
+
+    /// Sets the aperture radius from an f-number and the sensor height, in scene units (0.024
+    /// for a full frame sensor in meters)
+    ///
+    /// The focal length follows from the sensor height and the field of view.
+    #[allow(unused)]
+    pub fn set_f_stop(&mut self, f_stop: f32, sensor_height: f32) {
+        let focal_length = sensor_height * 0.5 / (self.fov.to_radians() * 0.5).tan();
+
+        self.aperture_radius = focal_length / (2.0 * f_stop);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Quat, Vec3};
+
+    use super::*;
+
+    #[test]
+    fn view_matrix_is_the_inverse_of_the_transform() {
+        let camera = Camera {
+            transform: Transform {
+                translation: Vec3::new(1.0, 2.0, 3.0),
+                rotation: Quat::from_rotation_y(std::f32::consts::FRAC_PI_2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let view = camera.view_matrix();
+        assert!(view.transform_point3(Vec3::new(1.0, 2.0, 3.0)).length() < 1e-5);
+        // Turned left by 90°, so -X in world space is straight ahead
+        let forward = view.transform_vector3(Vec3::NEG_X);
+        assert!(forward.abs_diff_eq(Vec3::NEG_Z, 1e-5), "{forward}");
+    }
+}
@@ -0,0 +1,250 @@
+use std::f32::consts::FRAC_PI_2;
+
+use glam::{Quat, Vec2, Vec3};
+use winit::{
+    dpi::PhysicalPosition,
+    event::{ElementState, KeyEvent, MouseButton, MouseScrollDelta, WindowEvent},
+    keyboard::{KeyCode, PhysicalKey},
+};
+
+use crate::camera::Camera;
+
+/// Radians the camera turns per pixel the mouse moves
+const LOOK_SENSITIVITY: f32 = 0.003;
+/// Keeps the camera from flipping over when looking straight up or down
+const MAX_PITCH: f32 = FRAC_PI_2 - 0.001;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CameraMode {
+    /// WASD to move, Q/E to go down/up, Shift to go faster and drag the left mouse button to
+    /// look around. The mouse wheel changes the speed.
+    Fly,
+    /// Drag the left mouse button to orbit around the target, the right or middle button to pan
+    /// and scroll to zoom
+    Orbit,
+}
+
+/// Moves a camera with keyboard and mouse input, Tab switches between the modes
+#[derive(Debug, Clone)]
+pub struct CameraController {
+    pub mode: CameraMode,
+    /// Units per second in fly mode
+    pub move_speed: f32,
+    /// The point orbit mode rotates around
+    pub target: Vec3,
+    position: Vec3,
+    yaw: f32,
+    pitch: f32,
+    /// Forward, right and up movement held down on the keyboard
+    movement: [KeyPair; 3],
+    fast: bool,
+    rotating: bool,
+    panning: bool,
+    cursor: Option<PhysicalPosition<f64>>,
+    changed: bool,
+}
+
+/// The state of two keys that move in opposite directions
+#[derive(Debug, Clone, Copy, Default)]
+struct KeyPair {
+    positive: bool,
+    negative: bool,
+}
+
+impl KeyPair {
+    fn value(self) -> f32 {
+        self.positive as u32 as f32 - self.negative as u32 as f32
+    }
+}
+
+impl CameraController {
+    /// Starts in fly mode at the position and orientation of `camera`, the orbit target is in
+    /// front of it at its focus distance
+    pub fn new(camera: &Camera) -> CameraController {
+        let forward = camera.transform.rotation * Vec3::NEG_Z;
+        let position = camera.transform.translation;
+
+        CameraController {
+            mode: CameraMode::Fly,
+            move_speed: 2.0,
+            target: position + forward * camera.focus_distance.max(1.0),
+            position,
+            yaw: (-forward.x).atan2(-forward.z),
+            pitch: forward
+                .y
+                .clamp(-1.0, 1.0)
+                .asin()
+                .clamp(-MAX_PITCH, MAX_PITCH),
+            movement: Default::default(),
+            fast: false,
+            rotating: false,
+            panning: false,
+            cursor: None,
+            changed: false,
+        }
+    }
+
+    /// Handles keyboard and mouse input, returns `true` if the event was used
+    pub fn window_event(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(code),
+                        state,
+                        repeat,
+                        ..
+                    },
+                ..
+            } => self.key(*code, *state == ElementState::Pressed, *repeat),
+            WindowEvent::MouseInput { state, button, .. } => {
+                let pressed = *state == ElementState::Pressed;
+                match button {
+                    MouseButton::Left => self.rotating = pressed,
+                    MouseButton::Right | MouseButton::Middle => self.panning = pressed,
+                    _ => return false,
+                }
+                true
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                if let Some(last) = self.cursor.replace(*position) {
+                    let delta =
+                        Vec2::new((position.x - last.x) as f32, (position.y - last.y) as f32);
+                    self.mouse_moved(delta);
+                }
+                true
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.cursor = None;
+                true
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / 40.0,
+                };
+                self.scroll(lines);
+                true
+            }
+            WindowEvent::Focused(false) => {
+                // Keys released while the window is unfocused never send an event
+                self.movement = Default::default();
+                self.fast = false;
+                self.rotating = false;
+                self.panning = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Applies the input since the last call to `camera`, `dt` is the frame time in seconds
+    ///
+    /// Returns `false` without touching the camera if nothing moved, so accumulation isn't reset
+    pub fn update(&mut self, camera: &mut Camera, dt: f32) -> bool {
+        let [forward, right, up] = self.movement.map(KeyPair::value);
+        if self.mode == CameraMode::Fly && (forward != 0.0 || right != 0.0 || up != 0.0) {
+            let speed = self.move_speed * if self.fast { 4.0 } else { 1.0 };
+            let rotation = self.rotation();
+            let direction =
+                rotation * Vec3::NEG_Z * forward + rotation * Vec3::X * right + Vec3::Y * up;
+
+            self.position += direction.normalize_or_zero() * speed * dt;
+            self.changed = true;
+        }
+
+        if !std::mem::take(&mut self.changed) {
+            return false;
+        }
+
+        camera.transform.translation = self.position;
+        camera.transform.rotation = self.rotation();
+        true
+    }
+
+    fn rotation(&self) -> Quat {
+        Quat::from_rotation_y(self.yaw) * Quat::from_rotation_x(self.pitch)
+    }
+
+    fn key(&mut self, code: KeyCode, pressed: bool, repeat: bool) -> bool {
+        let (pair, positive) = match code {
+            KeyCode::KeyW => (0, true),
+            KeyCode::KeyS => (0, false),
+            KeyCode::KeyD => (1, true),
+            KeyCode::KeyA => (1, false),
+            KeyCode::KeyE => (2, true),
+            KeyCode::KeyQ => (2, false),
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => {
+                self.fast = pressed;
+                return true;
+            }
+            KeyCode::Tab => {
+                if pressed && !repeat {
+                    self.toggle_mode();
+                }
+                return true;
+            }
+            _ => return false,
+        };
+
+        let keys = &mut self.movement[pair];
+        if positive {
+            keys.positive = pressed;
+        } else {
+            keys.negative = pressed;
+        }
+        true
+    }
+
+    fn toggle_mode(&mut self) {
+        let forward = self.rotation() * Vec3::NEG_Z;
+
+        self.mode = match self.mode {
+            CameraMode::Fly => {
+                // Orbit around the point in front of the camera at the old target's distance
+                let distance = self.position.distance(self.target).max(0.1);
+                self.target = self.position + forward * distance;
+                CameraMode::Orbit
+            }
+            CameraMode::Orbit => CameraMode::Fly,
+        };
+    }
+
+    fn mouse_moved(&mut self, delta: Vec2) {
+        if self.rotating {
+            let distance = self.position.distance(self.target);
+
+            self.yaw -= delta.x * LOOK_SENSITIVITY;
+            self.pitch = (self.pitch - delta.y * LOOK_SENSITIVITY).clamp(-MAX_PITCH, MAX_PITCH);
+
+            // Orbiting keeps the target in the center of the view
+            if self.mode == CameraMode::Orbit {
+                self.position = self.target - self.rotation() * Vec3::NEG_Z * distance;
+            }
+            self.changed = true;
+        } else if self.panning && self.mode == CameraMode::Orbit {
+            // Moves the target so it roughly follows the cursor
+            let distance = self.position.distance(self.target);
+            let rotation = self.rotation();
+            let offset = (rotation * Vec3::NEG_X * delta.x + rotation * Vec3::Y * delta.y)
+                * distance
+                * LOOK_SENSITIVITY;
+
+            self.position += offset;
+            self.target += offset;
+            self.changed = true;
+        }
+    }
+
+    fn scroll(&mut self, lines: f32) {
+        match self.mode {
+            CameraMode::Fly => self.move_speed *= 1.1f32.powf(lines),
+            CameraMode::Orbit => {
+                let distance = (self.position.distance(self.target) * 0.9f32.powf(lines)).max(0.01);
+
+                self.position = self.target - self.rotation() * Vec3::NEG_Z * distance;
+                self.changed = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use std::num::NonZeroU64;
+
+use glam::Mat4;
+
+use crate::{
+    aov::{Aov, AovSet},
+    renderer::RT_TARGET_FORMAT,
+    shader_types::{DENOISE_UNIFORM_STRIDE, GpuDenoiseUniform},
+};
+
+/// The most à-trous iterations, the last one reads pixels 256 pixels apart
+pub const MAX_DENOISER_ITERATIONS: u32 = 8;
+
+/// The size of one pixel of the denoiser history, matches `History` in `denoise.wgsl`
+const HISTORY_TEXEL_SIZE: u64 = 48;
+
+/// Options of the denoiser that filters the image before it's shown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DenoiserSettings {
+    pub mode: DenoiserMode,
+    /// The number of à-trous iterations, each one doubles the filter radius
+    pub iterations: u32,
+    /// How many standard deviations of the noise a luminance difference can be before it's kept
+    /// as an edge, larger values blur more
+    pub color_phi: f32,
+    /// The exponent of the normal similarity, larger values keep more geometric detail
+    pub normal_phi: f32,
+    /// How far the depth can deviate from the plane through a pixel before it's an edge
+    pub depth_phi: f32,
+    /// The albedo difference at which texture detail is kept
+    pub albedo_phi: f32,
+    /// The smallest weight of a new frame in the SVGF history, smaller values trade ghosting
+    /// for less noise while the camera moves
+    pub temporal_alpha: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenoiserMode {
+    Off,
+    /// Edge-avoiding à-trous wavelet filter guided by the normal, depth and albedo AOVs
+    ATrous,
+    /// Spatiotemporal variance-guided filtering, which also reuses the reprojected history of the
+    /// last frames so moving cameras stay clean
+    Svgf,
+}
+
+impl Default for DenoiserSettings {
+    fn default() -> Self {
+        Self {
+            mode: DenoiserMode::Off,
+            iterations: 5,
+            color_phi: 4.0,
+            normal_phi: 128.0,
+            depth_phi: 1.0,
+            albedo_phi: 0.1,
+            temporal_alpha: 0.2,
+        }
+    }
+}
+
+impl DenoiserMode {
+    /// Matches the `MODE_*` constants of `denoise.wgsl`
+    pub fn index(self) -> u32 {
+        match self {
+            DenoiserMode::Off => 0,
+            DenoiserMode::ATrous => 1,
+            DenoiserMode::Svgf => 2,
+        }
+    }
+
+    /// The AOVs the denoiser reads its features from
+    pub fn required_aovs(self) -> AovSet {
+        let features = AovSet::EMPTY
+            .with(Aov::Albedo)
+            .with(Aov::Normal)
+            .with(Aov::Depth);
+
+        match self {
+            DenoiserMode::Off => AovSet::EMPTY,
+            DenoiserMode::ATrous => features,
+            // Reprojection needs the world space positions
+            DenoiserMode::Svgf => features.with(Aov::Position),
+        }
+    }
+}
+
+/// What the denoiser filters in a frame
+pub struct DenoiserFrame<'a> {
+    pub rt_target: &'a wgpu::Texture,
+    pub rt_view: &'a wgpu::TextureView,
+    /// Has to hold the AOVs of `DenoiserMode::required_aovs()`
+    pub aov_buffer: &'a wgpu::Buffer,
+    pub aovs: AovSet,
+    pub settings: &'a DenoiserSettings,
+    /// `None` for projections that can't be expressed as a matrix
+    pub view_projection: Option<Mat4>,
+    /// The number of frames accumulated in the render target
+    pub accumulated_frames: u32,
+}
+
+/// Filters the render target in place after the raytracing pass
+pub struct Denoiser {
+    uniform_buffer: wgpu::Buffer,
+    shared_bind_group_layout: wgpu::BindGroupLayout,
+    temporal_bind_group_layout: wgpu::BindGroupLayout,
+    atrous_bind_group_layout: wgpu::BindGroupLayout,
+    temporal_pipeline: wgpu::ComputePipeline,
+    atrous_pipeline: wgpu::ComputePipeline,
+    /// Created for the size of the render target on the next frame
+    resources: Option<DenoiserResources>,
+    /// Which of the history buffers the next frame writes to
+    history_index: usize,
+    previous_view_projection: Option<Mat4>,
+}
+
+/// The buffers and bind groups that depend on the render target and the AOV buffer
+struct DenoiserResources {
+    shared_bind_group: wgpu::BindGroup,
+    /// One for each history buffer being written
+    temporal_bind_groups: [wgpu::BindGroup; 2],
+    /// Filters from the first to the second color buffer and back
+    atrous_bind_groups: [wgpu::BindGroup; 2],
+}
+
+impl Denoiser {
+    pub fn new(device: &wgpu::Device) -> Denoiser {
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Denoise Uniform Buffer"),
+            size: DENOISE_UNIFORM_STRIDE * (MAX_DENOISER_ITERATIONS as u64 + 1),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let storage_buffer = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let shared_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("denoise"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            // Every pass uses its own slot of the uniform buffer
+                            has_dynamic_offset: true,
+                            min_binding_size: NonZeroU64::new(
+                                std::mem::size_of::<GpuDenoiseUniform>() as u64,
+                            ),
+                        },
+                        count: None,
+                    },
+                    storage_buffer(1, true),
+                ],
+            });
+        let temporal_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("denoise_temporal"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    storage_buffer(1, true),
+                    storage_buffer(2, false),
+                    storage_buffer(3, false),
+                ],
+            });
+        let atrous_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("denoise_atrous"),
+                entries: &[
+                    storage_buffer(4, true),
+                    storage_buffer(5, false),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::StorageTexture {
+                            access: wgpu::StorageTextureAccess::WriteOnly,
+                            format: RT_TARGET_FORMAT,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shaders/denoise.wgsl"));
+        let create_pipeline = |entry_point, layout| {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(entry_point),
+                bind_group_layouts: &[&shared_bind_group_layout, layout],
+                push_constant_ranges: &[],
+            });
+
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+        let temporal_pipeline = create_pipeline("temporal", &temporal_bind_group_layout);
+        let atrous_pipeline = create_pipeline("atrous", &atrous_bind_group_layout);
+
+        Denoiser {
+            uniform_buffer,
+            shared_bind_group_layout,
+            temporal_bind_group_layout,
+            atrous_bind_group_layout,
+            temporal_pipeline,
+            atrous_pipeline,
+            resources: None,
+            history_index: 0,
+            previous_view_projection: None,
+        }
+    }
+
+    /// Drops the history and the bind groups, which have to follow a new render target or AOV
+    /// buffer
+    pub fn invalidate(&mut self) {
+        self.resources = None;
+        self.previous_view_projection = None;
+    }
+
+    /// Records the denoiser passes into `encoder`, they overwrite the render target with the
+    /// filtered image
+    pub fn encode(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        frame: &DenoiserFrame,
+    ) {
+        let settings = frame.settings;
+        let iterations = settings.iterations.clamp(1, MAX_DENOISER_ITERATIONS);
+
+        // Points can only be reprojected if both frames have a matrix
+        let previous_view_projection = self
+            .previous_view_projection
+            .filter(|_| frame.view_projection.is_some());
+        self.previous_view_projection = frame.view_projection;
+
+        let uniform = |iteration: u32| GpuDenoiseUniform {
+            previous_view_projection: previous_view_projection.unwrap_or_default(),
+            aov_mask: frame.aovs.bits(),
+            mode: settings.mode.index(),
+            reproject: previous_view_projection.is_some() as u32,
+            step: 1 << iteration,
+            color_phi: settings.color_phi,
+            normal_phi: settings.normal_phi,
+            depth_phi: settings.depth_phi,
+            albedo_phi: settings.albedo_phi,
+            temporal_alpha: settings.temporal_alpha.clamp(0.01, 1.0),
+            accumulated_frames: frame.accumulated_frames.max(1),
+            write_target: (iteration + 1 == iterations) as u32,
+            _p0: 0,
+        };
+        // The temporal pass uses the first slot, the iterations the ones after it
+        for slot in 0..=iterations {
+            queue.write_buffer(
+                &self.uniform_buffer,
+                slot as u64 * DENOISE_UNIFORM_STRIDE,
+                bytemuck::bytes_of(&uniform(slot.saturating_sub(1))),
+            );
+        }
+
+        let resources = self.resources.get_or_insert_with(|| {
+            create_resources(
+                device,
+                &self.uniform_buffer,
+                &self.shared_bind_group_layout,
+                &self.temporal_bind_group_layout,
+                &self.atrous_bind_group_layout,
+                frame,
+            )
+        });
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("denoise"),
+            timestamp_writes: None,
+        });
+        let workgroups_x = frame.rt_target.width().div_ceil(8);
+        let workgroups_y = frame.rt_target.height().div_ceil(8);
+
+        compute_pass.set_pipeline(&self.temporal_pipeline);
+        compute_pass.set_bind_group(0, &resources.shared_bind_group, &[0]);
+        compute_pass.set_bind_group(1, &resources.temporal_bind_groups[self.history_index], &[]);
+        compute_pass.dispatch_workgroups(workgroups_x, workgroups_y, 1);
+
+        compute_pass.set_pipeline(&self.atrous_pipeline);
+        for iteration in 0..iterations {
+            let offset = (iteration as u64 + 1) * DENOISE_UNIFORM_STRIDE;
+            compute_pass.set_bind_group(0, &resources.shared_bind_group, &[offset as u32]);
+            compute_pass.set_bind_group(
+                1,
+                &resources.atrous_bind_groups[iteration as usize % 2],
+                &[],
+            );
+            compute_pass.dispatch_workgroups(workgroups_x, workgroups_y, 1);
+        }
+
+        self.history_index = 1 - self.history_index;
+    }
+}
+
+fn create_resources(
+    device: &wgpu::Device,
+    uniform_buffer: &wgpu::Buffer,
+    shared_bind_group_layout: &wgpu::BindGroupLayout,
+    temporal_bind_group_layout: &wgpu::BindGroupLayout,
+    atrous_bind_group_layout: &wgpu::BindGroupLayout,
+    frame: &DenoiserFrame,
+) -> DenoiserResources {
+    let pixel_count = (frame.rt_target.width() * frame.rt_target.height()) as u64;
+    let create_buffer = |label, texel_size| {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: pixel_count * texel_size,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        })
+    };
+
+    // New buffers are zeroed, which marks every pixel as having no history
+    let histories = [
+        create_buffer("Denoise History", HISTORY_TEXEL_SIZE),
+        create_buffer("Denoise History", HISTORY_TEXEL_SIZE),
+    ];
+    // Color in `rgb` and variance in `a`
+    let colors = [
+        create_buffer("Denoise Color", 16),
+        create_buffer("Denoise Color", 16),
+    ];
+
+    let shared_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout: shared_bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: uniform_buffer,
+                    offset: 0,
+                    size: NonZeroU64::new(std::mem::size_of::<GpuDenoiseUniform>() as u64),
+                }),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: frame.aov_buffer.as_entire_binding(),
+            },
+        ],
+    });
+
+    let temporal_bind_group = |history: usize| {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: temporal_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(frame.rt_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: histories[1 - history].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: histories[history].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: colors[0].as_entire_binding(),
+                },
+            ],
+        })
+    };
+
+    let atrous_bind_group = |input: usize| {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: atrous_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: colors[input].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: colors[1 - input].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(frame.rt_view),
+                },
+            ],
+        })
+    };
+
+    DenoiserResources {
+        shared_bind_group,
+        temporal_bind_groups: [temporal_bind_group(0), temporal_bind_group(1)],
+        atrous_bind_groups: [atrous_bind_group(0), atrous_bind_group(1)],
+    }
+}
@@ -38,17 +38,26 @@ impl<T> DenseStorage<T> {
     }
 
     #[allow(unused)]
+    pub fn get_mut(&mut self, index: DenseStorageIndex) -> Option<&mut T> {
+        self.storage
+            .get_mut(index.0)
+            .filter(|(generation, _)| *generation == index.1)
+            .and_then(|(_, value)| value.as_mut())
+    }
+
+    /// Removes the value if the index is still valid, stale indices don't touch the value that
+    /// replaced theirs
     pub fn remove(&mut self, index: DenseStorageIndex) -> Option<T> {
-        if let Some((generation, value)) = self.storage.get_mut(index.0) {
-            if value.is_some() {
-                *generation += 1;
-                self.recycled_indices.push(index.0);
-            }
+        let (generation, value) = self
+            .storage
+            .get_mut(index.0)
+            .filter(|(generation, _)| *generation == index.1)?;
+        let value = value.take()?;
 
-            value.take()
-        } else {
-            None
-        }
+        *generation += 1;
+        self.recycled_indices.push(index.0);
+
+        Some(value)
     }
 
     pub fn iter(&self) -> std::slice::Iter<'_, (u32, Option<T>)> {
@@ -70,3 +79,23 @@ impl<T> IntoIterator for DenseStorage<T> {
         self.storage.into_iter()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_indices_are_ignored() {
+        let mut storage = DenseStorage::new();
+        let first = storage.push("first");
+        assert_eq!(storage.remove(first), Some("first"));
+
+        // The slot is recycled with a new generation
+        let second = storage.push("second");
+        assert_eq!(second.0, first.0);
+        assert_eq!(storage.get(first), None);
+        assert_eq!(storage.remove(first), None);
+        assert_eq!(storage.get(second), Some(&"second"));
+        assert_eq!(storage.remove(second), Some("second"));
+    }
+}
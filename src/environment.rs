@@ -0,0 +1,172 @@
+use std::path::Path;
+
+use glam::Vec4;
+
+/// An equirectangular HDR image that lights the scene from infinitely far away
+///
+/// The center of the image is in the -Z direction and the top row is straight up (+Y)
+#[derive(Debug, Clone)]
+pub struct Environment {
+    pub width: u32,
+    pub height: u32,
+    /// Linear RGBA pixels, row by row starting at the top
+    pub pixels: Vec<Vec4>,
+    /// Counterclockwise rotation around the +Y axis in radians
+    pub rotation: f32,
+    /// Multiplies the radiance of every pixel
+    pub intensity: f32,
+}
+
+impl Environment {
+    /// Loads an equirectangular Radiance `.hdr` image without any rotation
+    pub fn load(path: impl AsRef<Path>) -> Result<Environment, image::ImageError> {
+        let image = image::open(path)?.into_rgba32f();
+
+        Ok(Environment {
+            width: image.width(),
+            height: image.height(),
+            pixels: image.pixels().map(|pixel| Vec4::from(pixel.0)).collect(),
+            rotation: 0.0,
+            intensity: 1.0,
+        })
+    }
+
+    /// Builds the piecewise constant 2D distribution used to importance sample the environment
+    ///
+    /// Returns the marginal CDF over the rows (`height + 1` values) followed by the conditional CDF
+    /// over the pixels of every row (`width + 1` values each). Pixels are weighted by their
+    /// luminance and the solid angle their row covers, black images fall back to uniform CDFs.
+    pub fn sampling_distribution(&self) -> Vec<f32> {
+        let width = self.width as usize;
+        let height = self.height as usize;
+
+        let mut conditional = Vec::with_capacity(height * (width + 1));
+        let mut row_weights = Vec::with_capacity(height);
+
+        for (y, row) in self.pixels.chunks_exact(width).enumerate() {
+            // Rows near the poles are squeezed into a smaller solid angle
+            let sin_theta = (std::f64::consts::PI * (y as f64 + 0.5) / height as f64).sin();
+            let weights = row.iter().map(|pixel| {
+                let luminance = 0.2126 * pixel.x + 0.7152 * pixel.y + 0.0722 * pixel.z;
+                luminance.max(0.0) as f64 * sin_theta
+            });
+
+            row_weights.push(push_cdf(&mut conditional, weights));
+        }
+
+        let mut marginal = Vec::with_capacity(height + 1);
+        push_cdf(&mut marginal, row_weights.into_iter());
+
+        marginal.extend(conditional);
+        marginal
+    }
+}
+
+/// Appends the normalized CDF of `weights` to `cdf` and returns the sum of the weights
+fn push_cdf(cdf: &mut Vec<f32>, weights: impl ExactSizeIterator<Item = f64> + Clone) -> f64 {
+    let count = weights.len();
+    let total: f64 = weights.clone().sum();
+
+    cdf.push(0.0);
+    let mut sum = 0.0;
+    for (i, weight) in weights.enumerate() {
+        sum += weight;
+        cdf.push(if total > 0.0 {
+            (sum / total) as f32
+        } else {
+            (i + 1) as f32 / count as f32
+        });
+    }
+
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environment(width: u32, height: u32, pixel: impl Fn(u32, u32) -> Vec4) -> Environment {
+        Environment {
+            width,
+            height,
+            pixels: (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| pixel(x, y))
+                .collect(),
+            rotation: 0.0,
+            intensity: 1.0,
+        }
+    }
+
+    /// Splits the distribution into the marginal CDF and the conditional CDF of every row
+    fn split(environment: &Environment, distribution: &[f32]) -> (Vec<f32>, Vec<Vec<f32>>) {
+        let (marginal, conditional) = distribution.split_at(environment.height as usize + 1);
+        let rows = conditional
+            .chunks_exact(environment.width as usize + 1)
+            .map(<[f32]>::to_vec)
+            .collect();
+
+        (marginal.to_vec(), rows)
+    }
+
+    fn assert_cdf(cdf: &[f32]) {
+        assert_eq!(cdf[0], 0.0);
+        assert_eq!(*cdf.last().unwrap(), 1.0);
+        assert!(cdf.windows(2).all(|pair| pair[0] <= pair[1]), "{cdf:?}");
+    }
+
+    #[test]
+    fn cdfs_are_monotone_and_end_at_one() {
+        let environment = environment(7, 5, |x, y| {
+            Vec4::new((x * y) as f32, x as f32 * 0.1, 0.0, 1.0)
+        });
+        let distribution = environment.sampling_distribution();
+        assert_eq!(distribution.len(), 6 + 5 * 8);
+
+        let (marginal, rows) = split(&environment, &distribution);
+        assert_cdf(&marginal);
+        for row in &rows {
+            assert_cdf(row);
+        }
+    }
+
+    #[test]
+    fn bright_pixels_are_sampled_more() {
+        let environment = environment(4, 3, |x, y| {
+            if (x, y) == (2, 1) {
+                Vec4::splat(100.0)
+            } else {
+                Vec4::splat(1.0)
+            }
+        });
+        let (marginal, rows) = split(&environment, &environment.sampling_distribution());
+
+        let row_probabilities: Vec<_> = marginal.windows(2).map(|w| w[1] - w[0]).collect();
+        assert!(row_probabilities[1] > row_probabilities[0] + row_probabilities[2]);
+        let pixel_probability = rows[1][3] - rows[1][2];
+        assert!(pixel_probability > 0.9, "{pixel_probability}");
+    }
+
+    #[test]
+    fn rows_are_weighted_by_solid_angle() {
+        let environment = environment(2, 4, |_, _| Vec4::ONE);
+        let (marginal, _) = split(&environment, &environment.sampling_distribution());
+
+        // The rows at the poles cover less solid angle than the ones at the horizon
+        let probabilities: Vec<_> = marginal.windows(2).map(|w| w[1] - w[0]).collect();
+        assert!(probabilities[0] < probabilities[1]);
+        assert!((probabilities[0] - probabilities[3]).abs() < 1e-6);
+        assert!((probabilities[1] - probabilities[2]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn black_images_fall_back_to_uniform() {
+        let environment = environment(4, 2, |_, _| Vec4::ZERO);
+        let (marginal, rows) = split(&environment, &environment.sampling_distribution());
+
+        assert_eq!(marginal, [0.0, 0.5, 1.0]);
+        for row in rows {
+            assert_eq!(row, [0.0, 0.25, 0.5, 0.75, 1.0]);
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::path::Path;
+
+use glam::{Mat3, Mat4, Vec2, Vec3};
+use wgpu::naga::FastHashMap;
+
+use crate::{
+    camera::{Camera, Projection},
+    dense_storage::DenseStorageIndex,
+    load_error::LoadError,
+    material::Material,
+    mesh::{Mesh, Vertex},
+    mesh_object::MeshObject,
+    mesh_processing,
+    scene::Scene,
+    texture::{ColorSpace, Texture},
+    transform::Transform,
+};
+
+/// Imports the default scene of a glTF 2.0 or GLB file into `scene`
+///
+/// Every triangle primitive becomes its own mesh, every node that references a mesh becomes one
+/// mesh object per primitive, and the first camera found in the node hierarchy replaces the
+/// scene camera. Material textures are read with the first texture coordinate set. Returns the
+/// handles of the created mesh objects.
+pub fn import_gltf(
+    scene: &mut Scene,
+    path: impl AsRef<Path>,
+) -> Result<Vec<DenseStorageIndex>, LoadError> {
+    let (document, buffers, images) = gltf::import(path)?;
+
+    // The same image can be used as color and as data, which needs separate textures
+    let mut textures = FastHashMap::default();
+    let mut materials = Vec::new();
+    for material in document.materials() {
+        let mut load_texture = |texture: gltf::texture::Texture, color_space| {
+            let image = texture.source().index();
+
+            *textures
+                .entry((image, color_space))
+                .or_insert_with(|| scene.insert_texture(convert_image(&images[image], color_space)))
+        };
+
+        let pbr = material.pbr_metallic_roughness();
+        let material = Material {
+            albedo_texture: pbr
+                .base_color_texture()
+                .map(|info| load_texture(info.texture(), ColorSpace::Srgb)),
+            emissive_texture: material
+                .emissive_texture()
+                .map(|info| load_texture(info.texture(), ColorSpace::Srgb)),
+            metallic_roughness_texture: pbr
+                .metallic_roughness_texture()
+                .map(|info| load_texture(info.texture(), ColorSpace::Linear)),
+            normal_texture: material
+                .normal_texture()
+                .map(|normal| load_texture(normal.texture(), ColorSpace::Linear)),
+            ..convert_material(&material)
+        };
+
+        materials.push(scene.insert_material(material));
+    }
+    let mut default_material = None;
+
+    // (mesh handle, material handle) for every primitive of every mesh
+    let mut meshes = Vec::new();
+    for mesh in document.meshes() {
+        let mut primitives = Vec::new();
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let Some(positions) = reader.read_positions() else {
+                continue;
+            };
+            let positions: Vec<_> = positions.map(Vec3::from).collect();
+            let indices: Vec<_> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..positions.len() as u32).collect(),
+            };
+            let mut uvs = reader
+                .read_tex_coords(0)
+                .map(|uvs| uvs.into_f32().map(Vec2::from));
+            let mut normals = reader.read_normals().map(|normals| normals.map(Vec3::from));
+            let has_normals = normals.is_some();
+
+            let vertices: Vec<_> = positions
+                .into_iter()
+                .map(|pos| Vertex {
+                    pos,
+                    normal: normals
+                        .as_mut()
+                        .and_then(Iterator::next)
+                        .unwrap_or_default(),
+                    uv: uvs.as_mut().and_then(Iterator::next).unwrap_or_default(),
+                })
+                .collect();
+
+            let mesh = if has_normals {
+                Mesh { vertices, indices }
+            } else {
+                mesh_processing::generate_normals(&vertices, &indices, scene.normal_generation())
+            };
+
+            let material = match primitive.material().index() {
+                Some(i) => materials[i],
+                // Primitives without a material use the white default material from the spec
+                None => *default_material.get_or_insert_with(|| {
+                    scene.insert_material(Material {
+                        albedo: Vec3::ONE,
+                        ..Default::default()
+                    })
+                }),
+            };
+
+            primitives.push((scene.insert_mesh(mesh), material));
+        }
+
+        meshes.push(primitives);
+    }
+
+    let Some(gltf_scene) = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    else {
+        return Ok(Vec::new());
+    };
+
+    let mut mesh_objects = Vec::new();
+    let mut camera = None;
+    // Nodes are visited depth first in document order, so the first camera is the one listed first
+    let mut stack: Vec<_> = gltf_scene
+        .nodes()
+        .map(|node| (node, Mat4::IDENTITY))
+        .collect();
+    stack.reverse();
+
+    while let Some((node, parent_matrix)) = stack.pop() {
+        let matrix = parent_matrix * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+        if let Some(mesh) = node.mesh() {
+            let (transform, baked) = split_matrix(matrix);
+
+            for &(mesh, material) in &meshes[mesh.index()] {
+                // Sheared meshes get their own copy with the shear applied to the vertices
+                let mesh = match baked {
+                    Some(baked) => {
+                        let mesh = scene
+                            .get_mesh(mesh)
+                            .expect("imported meshes aren't removed");
+                        scene.insert_mesh(mesh_processing::transform_mesh(mesh, baked))
+                    }
+                    None => mesh,
+                };
+
+                mesh_objects.push(scene.insert_mesh_object(MeshObject {
+                    mesh,
+                    material,
+                    transform,
+                }));
+            }
+        }
+
+        if camera.is_none()
+            && let Some(gltf_camera) = node.camera()
+        {
+            camera = Some(convert_camera(&gltf_camera, matrix));
+        }
+
+        let children: Vec<_> = node.children().map(|child| (child, matrix)).collect();
+        stack.extend(children.into_iter().rev());
+    }
+
+    if let Some(camera) = camera {
+        *scene.camera_mut() = camera;
+    }
+
+    Ok(mesh_objects)
+}
+
+/// Splits a world matrix into a transform and a linear part that has to be applied to the
+/// vertices first
+///
+/// Rotated children of non-uniformly scaled nodes are sheared, which a scale, rotation and
+/// translation can't express. Those keep the translation and rotation in the transform and return
+/// the rest, which is `None` for every other matrix.
+fn split_matrix(matrix: Mat4) -> (Transform, Option<Mat3>) {
+    let transform = Transform::from(matrix);
+    let tolerance = 1e-4 * matrix.abs().to_cols_array().into_iter().fold(1.0, f32::max);
+    if Mat4::from(transform).abs_diff_eq(matrix, tolerance) {
+        return (transform, None);
+    }
+
+    let rotation = transform.rotation.normalize();
+    let rigid = Transform {
+        scale: Vec3::ONE,
+        rotation,
+        ..transform
+    };
+    let baked = Mat3::from_quat(rotation).transpose() * Mat3::from_mat4(matrix);
+
+    (rigid, Some(baked))
+}
+
+fn convert_material(material: &gltf::Material) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    let base_color = Vec3::from_slice(&pbr.base_color_factor());
+
+    Material {
+        albedo: base_color,
+        emissive: Vec3::from(material.emissive_factor()),
+        emissive_strength: material.emissive_strength().unwrap_or(1.0),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        ior: material.ior().unwrap_or(1.5),
+        transmission: material
+            .transmission()
+            .map_or(0.0, |transmission| transmission.transmission_factor()),
+        // The spec tints transmitted light by the base color
+        transmission_tint: base_color,
+        ..Default::default()
+    }
+}
+
+/// Expands a decoded glTF image to RGBA8, single and dual channel images are treated as gray
+fn convert_image(image: &gltf::image::Data, color_space: ColorSpace) -> Texture {
+    use gltf::image::Format;
+
+    let (channels, channel_size) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+
+    let to_u8 = |bytes: &[u8]| match channel_size {
+        1 => bytes[0],
+        2 => (u16::from_ne_bytes([bytes[0], bytes[1]]) >> 8) as u8,
+        _ => {
+            let value = f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            (value.clamp(0.0, 1.0) * 255.0).round() as u8
+        }
+    };
+
+    let pixels = image
+        .pixels
+        .chunks_exact(channels * channel_size)
+        .flat_map(|pixel| {
+            let channel = |i: usize| to_u8(&pixel[i * channel_size..]);
+
+            match channels {
+                1 => [channel(0), channel(0), channel(0), 255],
+                2 => [channel(0), channel(0), channel(0), channel(1)],
+                3 => [channel(0), channel(1), channel(2), 255],
+                _ => [channel(0), channel(1), channel(2), channel(3)],
+            }
+        })
+        .collect();
+
+    Texture {
+        width: image.width,
+        height: image.height,
+        pixels,
+        color_space,
+    }
+}
+
+fn convert_camera(camera: &gltf::Camera, matrix: Mat4) -> Camera {
+    let default_camera = Camera::default();
+    // Cameras ignore scale, only keep the position and orientation
+    let (_, rotation, translation) = matrix.to_scale_rotation_translation();
+    let transform = Transform {
+        translation,
+        rotation,
+        ..Default::default()
+    };
+
+    match camera.projection() {
+        gltf::camera::Projection::Perspective(perspective) => Camera {
+            fov: perspective.yfov().to_degrees(),
+            near_clip: perspective.znear(),
+            far_clip: perspective.zfar().unwrap_or(default_camera.far_clip),
+            transform,
+            ..default_camera
+        },
+        gltf::camera::Projection::Orthographic(orthographic) => Camera {
+            projection: Projection::Orthographic {
+                view_height: orthographic.ymag() * 2.0,
+            },
+            near_clip: orthographic.znear(),
+            far_clip: orthographic.zfar(),
+            transform,
+            ..default_camera
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Quat;
+
+    use super::*;
+
+    #[test]
+    fn split_matrix_keeps_plain_transforms() {
+        let matrix = Mat4::from_scale_rotation_translation(
+            Vec3::new(2.0, 1.0, 0.5),
+            Quat::from_rotation_y(0.3),
+            Vec3::new(1.0, 2.0, 3.0),
+        );
+
+        let (transform, baked) = split_matrix(matrix);
+        assert!(baked.is_none());
+        assert!(Mat4::from(transform).abs_diff_eq(matrix, 1e-5));
+    }
+
+    #[test]
+    fn split_matrix_bakes_shear() {
+        // A rotated child of a non-uniformly scaled parent
+        let parent = Mat4::from_scale(Vec3::new(2.0, 1.0, 1.0));
+        let child = Mat4::from_rotation_translation(
+            Quat::from_rotation_z(std::f32::consts::FRAC_PI_4),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        let matrix = parent * child;
+
+        let (transform, baked) = split_matrix(matrix);
+        let baked = baked.expect("the matrix is sheared");
+        assert_eq!(transform.scale, Vec3::ONE);
+
+        for point in [Vec3::X, Vec3::Y, Vec3::Z, Vec3::new(0.3, -0.7, 2.0)] {
+            let expected = matrix.transform_point3(point);
+            let actual = Mat4::from(transform).transform_point3(baked * point);
+            assert!(actual.abs_diff_eq(expected, 1e-5), "{actual} != {expected}");
+        }
+    }
+}
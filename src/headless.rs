@@ -0,0 +1,314 @@
+use std::path::Path;
+
+use glam::Vec4;
+use winit::dpi::PhysicalSize;
+
+use crate::{
+    image_writer::{self, ExrOptions, ImageLayer},
+    render_settings::RenderSettings,
+    renderer::{REQUIRED_FEATURES, Renderer},
+    save_error::SaveError,
+    scene::Scene,
+    tone_mapping::ToneMapping,
+};
+
+/// A linear RGBA image read back from the GPU
+#[derive(Debug, Clone)]
+pub struct RenderedImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Vec4>,
+    /// Extra channels that are only saved to OpenEXR files, next to the color
+    pub layers: Vec<ImageLayer>,
+}
+
+impl RenderedImage {
+    /// Saves the image using the format matching the file extension
+    ///
+    /// OpenEXR files keep the linear values and the extra layers, encoded with `exr_options`.
+    /// Radiance `.hdr` and `.pfm` files keep the linear color. Every other format is tone mapped
+    /// with `tone_mapping` and encoded as 8-bit sRGB.
+    pub fn save(
+        &self,
+        path: impl AsRef<Path>,
+        tone_mapping: &ToneMapping,
+        exr_options: &ExrOptions,
+    ) -> Result<(), SaveError> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        match extension.as_deref() {
+            Some("exr") => {
+                let color = ImageLayer {
+                    name: "rgba".to_string(),
+                    channels: &["R", "G", "B", "A"],
+                    pixels: self.pixels.clone(),
+                    full_precision: false,
+                };
+                let layers: Vec<_> = std::iter::once(color)
+                    .chain(self.layers.iter().cloned())
+                    .collect();
+
+                image_writer::write_exr(path, self.width, self.height, &layers, exr_options)?;
+            }
+            Some("pfm") => image_writer::write_pfm(path, self.width, self.height, &self.pixels)?,
+            Some("hdr") => {
+                let rgb = self
+                    .pixels
+                    .iter()
+                    .flat_map(|pixel| pixel.truncate().to_array());
+                image::Rgb32FImage::from_vec(self.width, self.height, rgb.collect())
+                    .expect("the pixel count should match the image size")
+                    .save(path)?;
+            }
+            _ => self.save_ldr(path, tone_mapping)?,
+        }
+
+        Ok(())
+    }
+
+    /// Tone maps the image and saves it as 8-bit sRGB
+    fn save_ldr(&self, path: &Path, tone_mapping: &ToneMapping) -> image::ImageResult<()> {
+        let rgba = self.pixels.iter().flat_map(|pixel| {
+            let srgb = tone_mapping
+                .apply(pixel.truncate())
+                .map(linear_to_srgb)
+                .extend(pixel.w);
+            (srgb.clamp(Vec4::ZERO, Vec4::ONE) * 255.0)
+                .round()
+                .to_array()
+                .map(|channel| channel as u8)
+        });
+        image::RgbaImage::from_vec(self.width, self.height, rgba.collect())
+            .expect("the pixel count should match the image size")
+            .save(path)
+    }
+}
+
+/// Returns an adapter without a surface that has every feature the renderer needs
+pub async fn request_adapter() -> Option<wgpu::Adapter> {
+    let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+    let adapter = instance
+        .request_adapter(&wgpu::RequestAdapterOptions::default())
+        .await
+        .ok()?;
+
+    adapter
+        .features()
+        .contains(REQUIRED_FEATURES)
+        .then_some(adapter)
+}
+
+/// Renders `scene` offscreen without creating a window, tracing `samples` rays per pixel in total
+///
+/// The samples are spread over several frames of at most `settings.samples_per_pixel` rays each,
+/// so a single dispatch doesn't run for too long
+pub async fn render_to_image(
+    scene: Scene,
+    width: u32,
+    height: u32,
+    samples: u32,
+    settings: RenderSettings,
+) -> RenderedImage {
+    let adapter = request_adapter()
+        .await
+        .expect("no GPU adapter supports ray queries");
+
+    let mut renderer = Renderer::new(&adapter, PhysicalSize::new(width, height), scene).await;
+
+    let mut remaining_samples = samples.max(1);
+    while remaining_samples > 0 {
+        let frame_samples = remaining_samples.min(settings.samples_per_pixel.max(1));
+        renderer.set_settings(RenderSettings {
+            samples_per_pixel: frame_samples,
+            ..settings
+        });
+        renderer.render();
+
+        remaining_samples -= frame_samples;
+    }
+
+    RenderedImage {
+        width,
+        height,
+        pixels: renderer.read_target(),
+        layers: renderer
+            .read_aovs()
+            .into_iter()
+            .map(|(aov, pixels)| ImageLayer {
+                name: aov.name().to_string(),
+                channels: aov.channels(),
+                pixels,
+                full_precision: aov.full_precision(),
+            })
+            .collect(),
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+
+    use super::*;
+    use crate::{
+        material::Material,
+        mesh_object::MeshObject,
+        texture::{ColorSpace, Texture},
+        tone_mapping::ToneMapOperator,
+    };
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("raytracing-{}-{name}", std::process::id()))
+    }
+
+    fn test_image() -> RenderedImage {
+        RenderedImage {
+            width: 2,
+            height: 1,
+            pixels: vec![Vec4::new(4.0, 0.5, 0.0, 1.0), Vec4::new(0.0, 0.0, 0.0, 1.0)],
+            layers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn hdr_keeps_values_above_one() {
+        let path = temp_path("linear.hdr");
+        test_image()
+            .save(&path, &ToneMapping::default(), &ExrOptions::default())
+            .unwrap();
+
+        let image = image::open(&path).unwrap().into_rgb32f();
+        std::fs::remove_file(&path).unwrap();
+
+        let pixel = image.get_pixel(0, 0);
+        assert!((pixel[0] - 4.0).abs() < 0.05, "{pixel:?}");
+        assert!((pixel[1] - 0.5).abs() < 0.01, "{pixel:?}");
+        assert_eq!(image.get_pixel(1, 0)[0], 0.0);
+    }
+
+    #[test]
+    fn png_is_tone_mapped_to_srgb() {
+        let path = temp_path("tone_mapped.png");
+        let tone_mapping = ToneMapping {
+            operator: ToneMapOperator::Clamp,
+            ..Default::default()
+        };
+        test_image()
+            .save(&path, &tone_mapping, &ExrOptions::default())
+            .unwrap();
+
+        let image = image::open(&path).unwrap().into_rgba8();
+        std::fs::remove_file(&path).unwrap();
+
+        // 0.5 is about 188 in sRGB
+        assert_eq!(image.get_pixel(0, 0).0, [255, 188, 0, 255]);
+        assert_eq!(image.get_pixel(1, 0).0, [0, 0, 0, 255]);
+    }
+
+    /// A scene whose only object is behind the camera, so every pixel shows the sky color
+    fn sky_scene() -> Scene {
+        let mut scene = Scene::default();
+        let cube = scene.load_mesh("assets/cube.obj").unwrap();
+        let material = scene.insert_material(Material::default());
+        scene.insert_mesh_object(MeshObject {
+            mesh: cube,
+            material,
+            transform: crate::transform::Transform {
+                translation: Vec3::new(0.0, 0.0, 10.0),
+                ..Default::default()
+            },
+        });
+
+        scene
+    }
+
+    #[test]
+    fn renders_edges_and_values_above_one() {
+        if pollster::block_on(request_adapter()).is_none() {
+            eprintln!("skipped, no GPU adapter supports ray queries");
+            return;
+        }
+
+        // Neither side is a multiple of the workgroup size
+        let settings = RenderSettings {
+            sky_color: Vec3::splat(4.0),
+            ..Default::default()
+        };
+        let image = pollster::block_on(render_to_image(sky_scene(), 13, 11, 1, settings));
+
+        assert_eq!(image.pixels.len(), 13 * 11);
+        for pixel in &image.pixels {
+            assert!((pixel.truncate() - Vec3::splat(4.0)).abs().max_element() < 0.01);
+        }
+    }
+
+    #[test]
+    fn edits_after_upload_are_rendered() {
+        let Some(adapter) = pollster::block_on(request_adapter()) else {
+            eprintln!("skipped, no GPU adapter supports ray queries");
+            return;
+        };
+
+        let mut renderer = pollster::block_on(Renderer::new(
+            &adapter,
+            PhysicalSize::new(13, 11),
+            sky_scene(),
+        ));
+        renderer.set_settings(RenderSettings {
+            sky_color: Vec3::splat(4.0),
+            samples_per_pixel: 1,
+            ..Default::default()
+        });
+        let center = |renderer: &mut Renderer| {
+            renderer.render();
+            renderer.read_target()[5 * 13 + 6].truncate()
+        };
+        assert!(center(&mut renderer).abs_diff_eq(Vec3::splat(4.0), 0.01));
+
+        // A black cube in front of the camera that only emits light through its texture
+        let scene = renderer.scene_mut();
+        let mesh = scene.load_mesh("assets/cube.obj").unwrap();
+        let texture = scene.insert_texture(Texture {
+            width: 1,
+            height: 1,
+            pixels: vec![128; 4],
+            color_space: ColorSpace::Linear,
+        });
+        let material = scene.insert_material(Material {
+            emissive: Vec3::ONE,
+            emissive_strength: 2.0,
+            ior: 1.0,
+            emissive_texture: Some(texture),
+            ..Default::default()
+        });
+        scene.insert_mesh_object(MeshObject {
+            mesh,
+            material,
+            transform: crate::transform::Transform {
+                translation: Vec3::new(0.0, 0.0, -3.0),
+                ..Default::default()
+            },
+        });
+        let textured = 2.0 * 128.0 / 255.0;
+        assert!(center(&mut renderer).abs_diff_eq(Vec3::splat(textured), 0.01));
+
+        // The material falls back to its untextured emission
+        renderer.scene_mut().remove_texture(texture);
+        assert!(center(&mut renderer).abs_diff_eq(Vec3::splat(2.0), 0.01));
+
+        // The cube is skipped while its mesh is gone
+        renderer.scene_mut().remove_mesh(mesh);
+        assert!(center(&mut renderer).abs_diff_eq(Vec3::splat(4.0), 0.01));
+    }
+}
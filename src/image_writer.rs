@@ -0,0 +1,241 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use exr::prelude::*;
+use glam::Vec4;
+
+/// The precision of the samples written to OpenEXR files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExrSampleType {
+    /// 16-bit floats, enough for colors and half the size
+    #[default]
+    Half,
+    /// 32-bit floats
+    Float,
+}
+
+/// The lossless compression of OpenEXR files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExrCompression {
+    None,
+    /// Deflate over blocks of 16 scanlines, good for rendered images
+    #[default]
+    Zip,
+    /// Wavelet compression, usually smaller for noisy images
+    Piz,
+}
+
+/// How OpenEXR files are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExrOptions {
+    pub sample_type: ExrSampleType,
+    pub compression: ExrCompression,
+}
+
+/// A named set of channels stored in the components of RGBA pixels, written as its own layer
+#[derive(Debug, Clone)]
+pub struct ImageLayer {
+    pub name: String,
+    /// The names of the channels, the first one is read from `x`, the second from `y` and so on
+    pub channels: &'static [&'static str],
+    pub pixels: Vec<Vec4>,
+    /// Depths and IDs lose too much in half floats, these layers are always written as 32-bit
+    /// floats
+    pub full_precision: bool,
+}
+
+/// Writes the layers to one multi-part OpenEXR file, the first layer is the one viewers show
+///
+/// The pixels of every layer are stored in rows from top to bottom.
+pub fn write_exr(
+    path: impl AsRef<Path>,
+    width: u32,
+    height: u32,
+    layers: &[ImageLayer],
+    options: &ExrOptions,
+) -> exr::error::UnitResult {
+    let size = (width as usize, height as usize);
+    let encoding = Encoding {
+        compression: match options.compression {
+            ExrCompression::None => Compression::Uncompressed,
+            ExrCompression::Zip => Compression::ZIP16,
+            ExrCompression::Piz => Compression::PIZ,
+        },
+        blocks: Blocks::ScanLines,
+        line_order: LineOrder::Increasing,
+    };
+
+    let layers: Vec<_> = layers
+        .iter()
+        .map(|layer| {
+            let channels = layer
+                .channels
+                .iter()
+                .enumerate()
+                .map(|(component, name)| {
+                    let samples = layer.pixels.iter().map(|pixel| pixel[component]);
+                    let samples =
+                        if layer.full_precision || options.sample_type == ExrSampleType::Float {
+                            FlatSamples::F32(samples.collect())
+                        } else {
+                            FlatSamples::F16(samples.map(f16::from_f32).collect())
+                        };
+
+                    AnyChannel::new(*name, samples)
+                })
+                .collect();
+
+            Layer::new(
+                size,
+                LayerAttributes::named(layer.name.as_str()),
+                encoding,
+                AnyChannels::sort(channels),
+            )
+        })
+        .collect();
+
+    let bounds = IntegerBounds::from_dimensions(size);
+    Image::from_layers(ImageAttributes::new(bounds), layers)
+        .write()
+        .to_file(path)
+}
+
+/// Writes the RGB components of the pixels, stored in rows from top to bottom, as a portable
+/// float map
+pub fn write_pfm(
+    path: impl AsRef<Path>,
+    width: u32,
+    height: u32,
+    pixels: &[Vec4],
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    // A negative scale marks the samples as little endian
+    write!(writer, "PF\n{width} {height}\n-1.0\n")?;
+
+    // PFM stores the bottom row first
+    for row in pixels.chunks_exact(width.max(1) as usize).rev() {
+        for pixel in row {
+            for channel in pixel.truncate().to_array() {
+                writer.write_all(&channel.to_le_bytes())?;
+            }
+        }
+    }
+
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("raytracing-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn pfm_is_bottom_up_little_endian() {
+        let path = temp_path("rows.pfm");
+        let pixels = [
+            Vec4::new(1.0, 2.0, 3.0, 1.0),
+            Vec4::new(4.0, 5.0, 6.0, 1.0),
+            Vec4::new(7.0, 8.0, 9.0, 1.0),
+            Vec4::new(10.0, 11.0, 12.0, 1.0),
+        ];
+        write_pfm(&path, 2, 2, &pixels).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let header = b"PF\n2 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let samples: Vec<f32> = bytes[header.len()..]
+            .chunks_exact(4)
+            .map(|sample| f32::from_le_bytes(sample.try_into().unwrap()))
+            .collect();
+        // The bottom row comes first and the alpha channel is dropped
+        assert_eq!(
+            samples,
+            [
+                7.0, 8.0, 9.0, 10.0, 11.0, 12.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0
+            ]
+        );
+    }
+
+    #[test]
+    fn exr_keeps_layers_and_precision() {
+        let path = temp_path("layers.exr");
+        let pixels = vec![
+            Vec4::new(0.25, 1.5, 3.0, 1.0),
+            Vec4::new(100.0, 0.0, 0.5, 1.0),
+        ];
+        let layers = [
+            ImageLayer {
+                name: "rgba".to_string(),
+                channels: &["R", "G", "B", "A"],
+                pixels: pixels.clone(),
+                full_precision: false,
+            },
+            ImageLayer {
+                name: "depth".to_string(),
+                channels: &["Z"],
+                pixels: vec![Vec4::splat(1234.567), Vec4::splat(0.001)],
+                full_precision: true,
+            },
+        ];
+        write_exr(&path, 2, 1, &layers, &ExrOptions::default()).unwrap();
+
+        let image = read_all_flat_layers_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let names: Vec<_> = image
+            .layer_data
+            .iter()
+            .map(|layer| layer.attributes.layer_name.as_ref().unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["rgba", "depth"]);
+
+        // Channels are sorted by name
+        let color = &image.layer_data[0].channel_data.list;
+        let channel_names: Vec<_> = color
+            .iter()
+            .map(|channel| channel.name.to_string())
+            .collect();
+        assert_eq!(channel_names, ["A", "B", "G", "R"]);
+        let FlatSamples::F16(red) = &color[3].sample_data else {
+            panic!("the color should be stored as half floats");
+        };
+        assert_eq!(red, &[f16::from_f32(0.25), f16::from_f32(100.0)]);
+
+        // Full precision layers stay 32-bit floats
+        let FlatSamples::F32(depth) = &image.layer_data[1].channel_data.list[0].sample_data else {
+            panic!("the depth should be stored as floats");
+        };
+        assert_eq!(depth, &[1234.567, 0.001]);
+    }
+
+    #[test]
+    fn exr_float_option_stores_floats() {
+        let path = temp_path("float.exr");
+        let layers = [ImageLayer {
+            name: "rgba".to_string(),
+            channels: &["R", "G", "B", "A"],
+            pixels: vec![Vec4::new(0.1, 0.2, 0.3, 1.0)],
+            full_precision: false,
+        }];
+        let options = ExrOptions {
+            sample_type: ExrSampleType::Float,
+            compression: ExrCompression::Piz,
+        };
+        write_exr(&path, 1, 1, &layers, &options).unwrap();
+
+        let image = read_all_flat_layers_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let red = &image.layer_data[0].channel_data.list[3].sample_data;
+        assert!(matches!(red, FlatSamples::F32(samples) if samples == &[0.1]));
+    }
+}
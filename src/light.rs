@@ -0,0 +1,48 @@
+use glam::Vec3;
+
+use crate::transform::Transform;
+
+/// A light source that is sampled directly with shadow rays
+///
+/// Lights are invisible to camera rays and only show up through the light they cast
+#[derive(Debug, Clone, Copy)]
+pub struct Light {
+    pub shape: LightShape,
+    pub color: Vec3,
+    /// Multiplies `color`, the unit depends on the shape
+    pub intensity: f32,
+    /// The position and orientation of the light, it points down its local -Z axis. The scale is
+    /// ignored, area lights get their size from `shape`.
+    pub transform: Transform,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightShape {
+    /// Emits equally in all directions, `intensity` is in watts per steradian
+    Point,
+    /// A point light limited to a cone, it fades out between the inner and outer half angles (in
+    /// radians)
+    #[allow(unused)]
+    Spot { inner_angle: f32, outer_angle: f32 },
+    /// Light from infinitely far away, like the sun. `intensity` is the irradiance in watts per
+    /// square meter and `angular_radius` (in radians) softens shadows.
+    #[allow(unused)]
+    Directional { angular_radius: f32 },
+    /// A one-sided rectangle in the local XY plane, `intensity` is the emitted radiance
+    #[allow(unused)]
+    Rect { width: f32, height: f32 },
+    /// A one-sided disk in the local XY plane, `intensity` is the emitted radiance
+    #[allow(unused)]
+    Disk { radius: f32 },
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self {
+            shape: LightShape::Point,
+            color: Vec3::ONE,
+            intensity: 1.0,
+            transform: Transform::default(),
+        }
+    }
+}
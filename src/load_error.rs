@@ -0,0 +1,54 @@
+use std::fmt;
+
+/// An error that occurred while loading a mesh or scene file
+#[derive(Debug)]
+pub enum LoadError {
+    /// The OBJ or MTL file couldn't be read or parsed
+    Obj(tobj::LoadError),
+    /// The glTF file or one of its buffers couldn't be read or parsed
+    Gltf(gltf::Error),
+    /// An image couldn't be read or decoded
+    Image(image::ImageError),
+    /// The file was read successfully but doesn't contain any meshes
+    NoMeshes,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Obj(error) => write!(f, "failed to load OBJ file: {error}"),
+            LoadError::Gltf(error) => write!(f, "failed to load glTF file: {error}"),
+            LoadError::Image(error) => write!(f, "failed to load image: {error}"),
+            LoadError::NoMeshes => write!(f, "the file doesn't contain any meshes"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Obj(error) => Some(error),
+            LoadError::Gltf(error) => Some(error),
+            LoadError::Image(error) => Some(error),
+            LoadError::NoMeshes => None,
+        }
+    }
+}
+
+impl From<tobj::LoadError> for LoadError {
+    fn from(value: tobj::LoadError) -> Self {
+        Self::Obj(value)
+    }
+}
+
+impl From<gltf::Error> for LoadError {
+    fn from(value: gltf::Error) -> Self {
+        Self::Gltf(value)
+    }
+}
+
+impl From<image::ImageError> for LoadError {
+    fn from(value: image::ImageError) -> Self {
+        Self::Image(value)
+    }
+}
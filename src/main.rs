@@ -1,32 +1,245 @@
+mod aov;
 mod camera;
+mod camera_controller;
+mod denoiser;
 mod dense_storage;
+mod environment;
+mod gltf_import;
+mod headless;
+mod image_writer;
+mod light;
+mod load_error;
 mod material;
 mod mesh;
 mod mesh_object;
+mod mesh_processing;
+mod obj_import;
+mod render_settings;
+mod renderer;
+mod save_error;
 mod scene;
 mod shader_types;
+mod sky;
 mod state;
+mod texture;
+mod tone_mapping;
 mod transform;
 
 use std::{sync::Arc, time::Instant};
 
+use aov::{Aov, AovSet};
+use camera_controller::CameraController;
+use denoiser::DenoiserMode;
 use glam::Vec3;
+use image_writer::{ExrCompression, ExrOptions, ExrSampleType};
 use material::Material;
 use mesh_object::MeshObject;
+use render_settings::RenderSettings;
 use scene::Scene;
+use tone_mapping::ToneMapOperator;
 use winit::{
     application::ApplicationHandler,
-    event::WindowEvent,
+    event::{ElementState, KeyEvent, WindowEvent},
     event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
+    keyboard::{KeyCode, PhysicalKey},
     window::{Window, WindowId},
 };
 
 use crate::state::State;
 
+fn build_scene() -> Scene {
+    let mut scene = Scene::default();
+
+    let sphere = scene
+        .load_mesh("assets/sphere.obj")
+        .expect("The sphere obj should exist");
+    let cube = scene
+        .load_mesh("assets/cube.obj")
+        .expect("The cube obj should exist");
+    let blue_mat = scene.insert_material(Material {
+        albedo: Vec3::new(66.0, 135.0, 245.0) / 255.0,
+        ..Default::default()
+    });
+    let white_emissive_mat = scene.insert_material(Material {
+        emissive: Vec3::new(1.0, 1.0, 1.0),
+        emissive_strength: 3.0,
+        ..Default::default()
+    });
+    let gray_mat = scene.insert_material(Material {
+        albedo: Vec3::new(127.0, 127.0, 127.0) / 255.0,
+        ..Default::default()
+    });
+
+    scene.insert_mesh_object(MeshObject {
+        mesh: sphere,
+        material: blue_mat,
+        transform: transform::Transform {
+            translation: Vec3::new(1.0, -0.5, -3.0),
+            ..Default::default()
+        },
+    });
+
+    scene.insert_mesh_object(MeshObject {
+        mesh: cube,
+        material: white_emissive_mat,
+        transform: transform::Transform {
+            translation: Vec3::new(0.0, 1.5, -3.0),
+            ..Default::default()
+        },
+    });
+
+    scene.insert_mesh_object(MeshObject {
+        mesh: cube,
+        material: gray_mat,
+        transform: transform::Transform {
+            translation: Vec3::new(0.0, -1.5, -3.0),
+            scale: Vec3::new(10.0, 1.0, 10.0),
+            ..Default::default()
+        },
+    });
+
+    scene
+}
+
+/// Options for rendering a single image to a file instead of opening a window
+struct HeadlessArgs {
+    output: String,
+    /// An OBJ, glTF or GLB file rendered instead of the built-in scene
+    scene: Option<String>,
+    width: u32,
+    height: u32,
+    samples: u32,
+    settings: RenderSettings,
+    exr_options: ExrOptions,
+}
+
+impl HeadlessArgs {
+    /// Parses `--output <path> [--scene <path>] [--width <px>] [--height <px>] [--samples <n>]
+    /// [--bounces <n>] [--seed <n>] [--firefly-clamp <value>] [--exposure <stops>]
+    /// [--tone-mapping <clamp|reinhard|aces|agx|neutral>] [--white-balance <kelvin>]
+    /// [--exr-precision <half|float>] [--exr-compression <none|zip|piz>] [--aovs <all|name,...>]
+    /// [--denoise <off|atrous|svgf>]`
+    ///
+    /// Returns `None` when no output path is given
+    fn from_args() -> Option<HeadlessArgs> {
+        let mut output = None;
+        let mut scene = None;
+        let mut width = 1280;
+        let mut height = 720;
+        let mut samples = 100;
+        let mut settings = RenderSettings::default();
+        let mut exr_options = ExrOptions::default();
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .unwrap_or_else(|| panic!("{arg} expects a value"))
+            };
+
+            match arg.as_str() {
+                "--output" | "-o" => output = Some(value()),
+                "--scene" => scene = Some(value()),
+                "--width" => width = parse_number(&arg, value()),
+                "--height" => height = parse_number(&arg, value()),
+                "--samples" => samples = parse_number(&arg, value()),
+                "--bounces" => settings.max_bounces = parse_number(&arg, value()),
+                "--seed" => settings.seed = parse_number(&arg, value()),
+                "--firefly-clamp" => settings.firefly_clamp = parse_number(&arg, value()),
+                "--exposure" => settings.tone_mapping.exposure = parse_number(&arg, value()),
+                "--tone-mapping" => {
+                    settings.tone_mapping.operator = match value().as_str() {
+                        "clamp" => ToneMapOperator::Clamp,
+                        "reinhard" => ToneMapOperator::Reinhard,
+                        "aces" => ToneMapOperator::AcesFilmic,
+                        "agx" => ToneMapOperator::Agx,
+                        "neutral" => ToneMapOperator::KhronosPbrNeutral,
+                        operator => panic!("unknown tone mapping operator `{operator}`"),
+                    }
+                }
+                "--white-balance" => {
+                    settings.tone_mapping.temperature = parse_number(&arg, value())
+                }
+                "--exr-precision" => {
+                    exr_options.sample_type = match value().as_str() {
+                        "half" => ExrSampleType::Half,
+                        "float" => ExrSampleType::Float,
+                        precision => panic!("unknown EXR precision `{precision}`"),
+                    }
+                }
+                "--aovs" => settings.aovs = parse_aovs(&value()),
+                "--exr-compression" => {
+                    exr_options.compression = match value().as_str() {
+                        "none" => ExrCompression::None,
+                        "zip" => ExrCompression::Zip,
+                        "piz" => ExrCompression::Piz,
+                        compression => panic!("unknown EXR compression `{compression}`"),
+                    }
+                }
+                "--denoise" => {
+                    settings.denoiser.mode = match value().as_str() {
+                        "off" => DenoiserMode::Off,
+                        "atrous" => DenoiserMode::ATrous,
+                        "svgf" => DenoiserMode::Svgf,
+                        mode => panic!("unknown denoiser `{mode}`"),
+                    }
+                }
+                _ => panic!("unknown argument `{arg}`"),
+            }
+        }
+
+        Some(HeadlessArgs {
+            output: output?,
+            scene,
+            width,
+            height,
+            samples,
+            settings,
+            exr_options,
+        })
+    }
+}
+
+/// Parses `all` or a comma separated list of `Aov::name()`s
+fn parse_aovs(value: &str) -> AovSet {
+    if value == "all" {
+        return AovSet::ALL;
+    }
+
+    value.split(',').fold(AovSet::default(), |aovs, name| {
+        let aov = Aov::ALL
+            .into_iter()
+            .find(|aov| aov.name() == name)
+            .unwrap_or_else(|| panic!("unknown AOV `{name}`"));
+        aovs.with(aov)
+    })
+}
+
+/// Loads a scene file, glTF files keep the camera they define
+fn load_scene(path: &str) -> Scene {
+    let mut scene = Scene::default();
+    let result = if path.to_ascii_lowercase().ends_with(".obj") {
+        scene.load_obj(path)
+    } else {
+        scene.load_gltf(path)
+    };
+    result.unwrap_or_else(|error| panic!("failed to load {path}: {error}"));
+
+    scene
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str, value: String) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("{arg} expects a number, got `{value}`"))
+}
+
 #[derive(Default)]
 struct App {
     state: Option<State>,
+    camera_controller: Option<CameraController>,
     last_time: Option<Instant>,
+    last_frame: Option<Instant>,
     frame_count: u32,
 }
 
@@ -42,57 +255,8 @@ impl ApplicationHandler for App {
                 .unwrap(),
         );
 
-        let mut scene = Scene::default();
-
-        let sphere = scene
-            .load_mesh("assets/sphere.obj")
-            .expect("The sphere obj should exist");
-        let cube = scene
-            .load_mesh("assets/cube.obj")
-            .expect("The cube obj should exist");
-        let blue_mat = scene.insert_material(Material {
-            albedo: Vec3::new(66.0, 135.0, 245.0) / 255.0,
-            ..Default::default()
-        });
-        let white_emissive_mat = scene.insert_material(Material {
-            emissive: Vec3::new(1.0, 1.0, 1.0),
-            emissive_strength: 3.0,
-            ..Default::default()
-        });
-        let gray_mat = scene.insert_material(Material {
-            albedo: Vec3::new(127.0, 127.0, 127.0) / 255.0,
-            ..Default::default()
-        });
-
-        scene.insert_mesh_object(MeshObject {
-            mesh: sphere,
-            material: blue_mat,
-            transform: transform::Transform {
-                translation: Vec3::new(1.0, -0.5, -3.0),
-                ..Default::default()
-            },
-        });
-
-        scene.insert_mesh_object(MeshObject {
-            mesh: cube,
-            material: white_emissive_mat,
-            transform: transform::Transform {
-                translation: Vec3::new(0.0, 1.5, -3.0),
-                ..Default::default()
-            },
-        });
-
-        scene.insert_mesh_object(MeshObject {
-            mesh: cube,
-            material: gray_mat,
-            transform: transform::Transform {
-                translation: Vec3::new(0.0, -1.5, -3.0),
-                scale: Vec3::new(10.0, 1.0, 10.0),
-                ..Default::default()
-            },
-        });
-
-        let state = pollster::block_on(State::new(window.clone(), scene));
+        let state = pollster::block_on(State::new(window.clone(), build_scene()));
+        self.camera_controller = Some(CameraController::new(state.scene().camera()));
         self.state = Some(state);
 
         window.request_redraw();
@@ -120,13 +284,94 @@ impl ApplicationHandler for App {
 
                 self.frame_count += 1;
 
+                let now = Instant::now();
+                let dt = self
+                    .last_frame
+                    .replace(now)
+                    .map_or(0.0, |last_frame| (now - last_frame).as_secs_f32());
+                // Only a moved camera touches the scene, which restarts accumulation
+                let mut camera = *state.scene().camera();
+                if let Some(camera_controller) = &mut self.camera_controller
+                    && camera_controller.update(&mut camera, dt)
+                {
+                    *state.scene_mut().camera_mut() = camera;
+                }
+
                 state.render();
                 state.get_window().request_redraw();
             }
             WindowEvent::Resized(size) => {
                 state.resize(size);
             }
-            _ => (),
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(KeyCode::KeyR),
+                        state: ElementState::Pressed,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                // Toggles a half resolution preview that is upscaled to the window
+                let settings = *state.settings();
+                let resolution_scale = if settings.resolution_scale < 1.0 {
+                    1.0
+                } else {
+                    0.5
+                };
+                state.set_settings(RenderSettings {
+                    resolution_scale,
+                    ..settings
+                });
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(KeyCode::KeyV),
+                        state: ElementState::Pressed,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                // Cycles through the AOVs and back to the color
+                let settings = *state.settings();
+                let view_aov = match settings.view_aov {
+                    None => Some(Aov::ALL[0]),
+                    Some(aov) => Aov::ALL.get(aov.index() as usize + 1).copied(),
+                };
+                println!("Viewing {}", view_aov.map_or("color", Aov::name));
+                state.set_settings(RenderSettings {
+                    view_aov,
+                    ..settings
+                });
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(KeyCode::KeyN),
+                        state: ElementState::Pressed,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                // Cycles through the denoisers
+                let mut settings = *state.settings();
+                settings.denoiser.mode = match settings.denoiser.mode {
+                    DenoiserMode::Off => DenoiserMode::ATrous,
+                    DenoiserMode::ATrous => DenoiserMode::Svgf,
+                    DenoiserMode::Svgf => DenoiserMode::Off,
+                };
+                println!("Denoiser: {:?}", settings.denoiser.mode);
+                state.set_settings(settings);
+            }
+            event => {
+                if let Some(camera_controller) = &mut self.camera_controller {
+                    camera_controller.window_event(&event);
+                }
+            }
         }
     }
 }
@@ -134,6 +379,26 @@ impl ApplicationHandler for App {
 fn main() {
     env_logger::init();
 
+    if let Some(args) = HeadlessArgs::from_args() {
+        let scene = match &args.scene {
+            Some(path) => load_scene(path),
+            None => build_scene(),
+        };
+        let image = pollster::block_on(headless::render_to_image(
+            scene,
+            args.width,
+            args.height,
+            args.samples,
+            args.settings,
+        ));
+        image
+            .save(&args.output, &args.settings.tone_mapping, &args.exr_options)
+            .expect("failed to save the rendered image");
+        println!("Saved render to {}", args.output);
+
+        return;
+    }
+
     let event_loop = EventLoop::new().unwrap();
 
     event_loop.set_control_flow(ControlFlow::Poll);
@@ -1,8 +1,51 @@
 use glam::Vec3;
 
-#[derive(Debug, Default, Clone, Copy)]
+use crate::dense_storage::DenseStorageIndex;
+
+#[derive(Debug, Clone, Copy)]
 pub struct Material {
     pub albedo: Vec3,
     pub emissive: Vec3,
     pub emissive_strength: f32,
+    /// Blends between a dielectric (`0.0`) and a metal (`1.0`) that tints reflections by `albedo`
+    pub metallic: f32,
+    /// Perceptual roughness from mirror-like (`0.0`) to fully rough (`1.0`)
+    pub roughness: f32,
+    /// Index of refraction, also sets the strength of reflections on dielectrics
+    pub ior: f32,
+    /// How much light passes through the surface instead of being diffusely scattered
+    pub transmission: f32,
+    /// Multiplies light that is refracted through the surface
+    pub transmission_tint: Vec3,
+    /// The chance that a ray stops at the surface, the others pass straight through it like
+    /// through the holes of a cut-out. `1.0` is fully opaque.
+    pub opacity: f32,
+    /// Multiplies `albedo`
+    pub albedo_texture: Option<DenseStorageIndex>,
+    /// Multiplies `emissive`
+    pub emissive_texture: Option<DenseStorageIndex>,
+    /// Multiplies `roughness` by the green channel and `metallic` by the blue channel like glTF
+    pub metallic_roughness_texture: Option<DenseStorageIndex>,
+    /// A tangent space normal map with +Y pointing up in the image
+    pub normal_texture: Option<DenseStorageIndex>,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            albedo: Vec3::ZERO,
+            emissive: Vec3::ZERO,
+            emissive_strength: 0.0,
+            metallic: 0.0,
+            roughness: 1.0,
+            ior: 1.5,
+            transmission: 0.0,
+            transmission_tint: Vec3::ONE,
+            opacity: 1.0,
+            albedo_texture: None,
+            emissive_texture: None,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use glam::Vec3;
+use glam::{Vec2, Vec3};
 
 #[derive(Debug, Default, Clone)]
 pub struct Mesh {
@@ -10,4 +10,6 @@ pub struct Mesh {
 pub struct Vertex {
     pub pos: Vec3,
     pub normal: Vec3,
+    /// Texture coordinates with the origin in the top left corner of the image
+    pub uv: Vec2,
 }
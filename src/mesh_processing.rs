@@ -0,0 +1,305 @@
+use glam::{Mat3, Vec3};
+use wgpu::naga::FastHashMap;
+
+use crate::mesh::{Mesh, Vertex};
+
+/// How normals are generated for meshes that don't come with any
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalGeneration {
+    /// Every vertex gets the average of the surrounding face normals, weighted by face area
+    #[allow(unused)]
+    Smooth,
+    /// Faces get their own normal, but neighboring faces that meet at an angle (in radians)
+    /// below `crease_angle` are smoothed together
+    Flat { crease_angle: f32 },
+}
+
+impl Default for NormalGeneration {
+    fn default() -> Self {
+        Self::Flat {
+            crease_angle: 30f32.to_radians(),
+        }
+    }
+}
+
+/// The normal of vertices that only belong to degenerate faces, the shader can't normalize zero
+/// normals
+const FALLBACK_NORMAL: Vec3 = Vec3::Y;
+
+/// Builds a mesh from triangle list vertices and indices, replacing the vertex normals
+///
+/// Vertices that share a position are treated as connected even if they were split (e.g. at UV
+/// seams), so normals stay continuous across them. Flat normals can add vertices where creases
+/// split a shared vertex.
+pub fn generate_normals(vertices: &[Vertex], indices: &[u32], mode: NormalGeneration) -> Mesh {
+    // The length of the cross product is twice the triangle area, which gives area weighting
+    let face_normals: Vec<_> = indices
+        .chunks_exact(3)
+        .map(|triangle| {
+            let [p0, p1, p2] = [0, 1, 2].map(|i| vertices[triangle[i] as usize].pos);
+            (p1 - p0).cross(p2 - p0)
+        })
+        .collect();
+
+    let welded_indices = weld_positions(vertices);
+    let welded_count = welded_indices.iter().max().map_or(0, |max| max + 1);
+
+    let mut vertex_faces = vec![Vec::new(); welded_count];
+    for (face, triangle) in indices.chunks_exact(3).enumerate() {
+        for &i in triangle {
+            vertex_faces[welded_indices[i as usize]].push(face);
+        }
+    }
+
+    match mode {
+        NormalGeneration::Smooth => {
+            let welded_normals: Vec<_> = vertex_faces
+                .iter()
+                .map(|faces| {
+                    faces
+                        .iter()
+                        .map(|&face| face_normals[face])
+                        .sum::<Vec3>()
+                        .try_normalize()
+                        .unwrap_or(FALLBACK_NORMAL)
+                })
+                .collect();
+
+            Mesh {
+                vertices: vertices
+                    .iter()
+                    .zip(&welded_indices)
+                    .map(|(vertex, &welded_i)| Vertex {
+                        normal: welded_normals[welded_i],
+                        ..*vertex
+                    })
+                    .collect(),
+                indices: indices.to_vec(),
+            }
+        }
+        NormalGeneration::Flat { crease_angle } => {
+            let min_cos = crease_angle.cos();
+            let unit_face_normals: Vec<_> = face_normals
+                .iter()
+                .map(|normal| normal.normalize_or_zero())
+                .collect();
+
+            let mut new_vertices = Vec::new();
+            let mut new_indices = Vec::with_capacity(indices.len());
+            // (original vertex index, normal bits) -> new vertex index
+            let mut vertex_map = FastHashMap::default();
+
+            for (face, triangle) in indices.chunks_exact(3).enumerate() {
+                // Degenerate faces have no direction to compare, they are smoothed with all
+                // their neighbors instead
+                let degenerate = unit_face_normals[face] == Vec3::ZERO;
+
+                for &i in triangle {
+                    let normal = vertex_faces[welded_indices[i as usize]]
+                        .iter()
+                        .filter(|&&other| {
+                            degenerate
+                                || unit_face_normals[other].dot(unit_face_normals[face]) >= min_cos
+                        })
+                        .map(|&other| face_normals[other])
+                        .sum::<Vec3>()
+                        .try_normalize()
+                        .unwrap_or(FALLBACK_NORMAL);
+
+                    let new_index = *vertex_map
+                        .entry((i, normal.to_array().map(f32::to_bits)))
+                        .or_insert_with(|| {
+                            new_vertices.push(Vertex {
+                                normal,
+                                ..vertices[i as usize]
+                            });
+                            new_vertices.len() as u32 - 1
+                        });
+                    new_indices.push(new_index);
+                }
+            }
+
+            Mesh {
+                vertices: new_vertices,
+                indices: new_indices,
+            }
+        }
+    }
+}
+
+/// Applies a linear transform to the positions and normals of a mesh
+///
+/// Mirroring transforms flip the winding order so faces keep pointing the same way as their
+/// normals.
+pub fn transform_mesh(mesh: &Mesh, matrix: Mat3) -> Mesh {
+    let normal_matrix = matrix.inverse().transpose();
+
+    let vertices = mesh
+        .vertices
+        .iter()
+        .map(|vertex| Vertex {
+            pos: matrix * vertex.pos,
+            normal: (normal_matrix * vertex.normal).normalize_or_zero(),
+            ..*vertex
+        })
+        .collect();
+
+    let mut indices = mesh.indices.clone();
+    if matrix.determinant() < 0.0 {
+        for triangle in indices.chunks_exact_mut(3) {
+            triangle.swap(1, 2);
+        }
+    }
+
+    Mesh { vertices, indices }
+}
+
+/// Returns an index for every vertex that is shared by all vertices with the same position
+fn weld_positions(vertices: &[Vertex]) -> Vec<usize> {
+    let mut welded = FastHashMap::default();
+
+    vertices
+        .iter()
+        .map(|vertex| {
+            let next_index = welded.len();
+            *welded
+                .entry(vertex.pos.to_array().map(f32::to_bits))
+                .or_insert(next_index)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_mesh_keeps_normals_perpendicular() {
+        // A triangle in the plane x = y, sheared along x
+        let mesh = Mesh {
+            vertices: [Vec3::ZERO, Vec3::new(1.0, 1.0, 0.0), Vec3::Z]
+                .map(|pos| Vertex {
+                    pos,
+                    normal: Vec3::new(1.0, -1.0, 0.0).normalize(),
+                    ..Default::default()
+                })
+                .to_vec(),
+            indices: vec![0, 1, 2],
+        };
+        let shear = Mat3::from_cols(Vec3::X, Vec3::new(0.5, 1.0, 0.0), Vec3::Z);
+
+        let transformed = transform_mesh(&mesh, shear);
+        let [p0, p1, p2] = [0, 1, 2].map(|i| transformed.vertices[i].pos);
+        for vertex in &transformed.vertices {
+            assert!((p1 - p0).dot(vertex.normal).abs() < 1e-5);
+            assert!((p2 - p0).dot(vertex.normal).abs() < 1e-5);
+        }
+        assert_eq!(transformed.indices, [0, 1, 2]);
+    }
+
+    #[test]
+    fn transform_mesh_flips_mirrored_winding() {
+        let mesh = Mesh {
+            vertices: vec![Vertex::default(); 3],
+            indices: vec![0, 1, 2],
+        };
+
+        let mirrored = transform_mesh(&mesh, Mat3::from_diagonal(Vec3::new(-1.0, 1.0, 1.0)));
+        assert_eq!(mirrored.indices, [0, 2, 1]);
+    }
+
+    fn vertices(positions: &[Vec3]) -> Vec<Vertex> {
+        positions
+            .iter()
+            .map(|&pos| Vertex {
+                pos,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Two triangles sharing the edge along X, the second one folded up by `angle` radians
+    fn folded_quad(angle: f32) -> (Vec<Vertex>, Vec<u32>) {
+        let folded = Vec3::new(0.0, angle.sin(), -angle.cos());
+        let positions = [Vec3::ZERO, Vec3::X, Vec3::Z, Vec3::ZERO, Vec3::X, folded];
+
+        // The shared edge is split like at a UV seam
+        (vertices(&positions), vec![0, 2, 1, 3, 4, 5])
+    }
+
+    fn normal_at(mesh: &Mesh, pos: Vec3) -> Vec<Vec3> {
+        mesh.vertices
+            .iter()
+            .filter(|vertex| vertex.pos == pos)
+            .map(|vertex| vertex.normal)
+            .collect()
+    }
+
+    #[test]
+    fn flat_normals_split_at_creases() {
+        let (vertices, indices) = folded_quad(90f32.to_radians());
+        let mesh = generate_normals(&vertices, &indices, NormalGeneration::default());
+
+        // Each face keeps its own normal along the shared edge
+        let normals = normal_at(&mesh, Vec3::X);
+        assert_eq!(normals.len(), 2);
+        assert!(normals.iter().any(|n| n.abs_diff_eq(Vec3::Y, 1e-5)));
+        assert!(normals.iter().any(|n| n.abs_diff_eq(Vec3::Z, 1e-5)));
+    }
+
+    #[test]
+    fn flat_normals_smooth_below_the_crease_angle() {
+        let (vertices, indices) = folded_quad(20f32.to_radians());
+        let mesh = generate_normals(&vertices, &indices, NormalGeneration::default());
+
+        // The split vertices of the seam are welded and share one normal
+        let normals = normal_at(&mesh, Vec3::X);
+        assert_eq!(normals.len(), 2);
+        assert!(normals[0].abs_diff_eq(normals[1], 1e-6));
+        let expected = Vec3::new(0.0, 10f32.to_radians().cos(), 10f32.to_radians().sin());
+        assert!(normals[0].abs_diff_eq(expected, 1e-5), "{}", normals[0]);
+
+        // Vertices only used by one face keep its normal
+        assert!(normal_at(&mesh, Vec3::Z)[0].abs_diff_eq(Vec3::Y, 1e-5));
+    }
+
+    #[test]
+    fn smooth_normals_are_continuous_across_seams() {
+        let (vertices, indices) = folded_quad(90f32.to_radians());
+        let mesh = generate_normals(&vertices, &indices, NormalGeneration::Smooth);
+
+        assert_eq!(mesh.vertices.len(), vertices.len());
+        let normals = normal_at(&mesh, Vec3::ZERO);
+        assert!(normals[0].abs_diff_eq(normals[1], 1e-6));
+        assert!(normals[0].abs_diff_eq(Vec3::new(0.0, 1.0, 1.0).normalize(), 1e-5));
+    }
+
+    #[test]
+    fn degenerate_triangles_get_unit_normals() {
+        // A regular triangle and a degenerate one sharing its first vertex, plus an isolated
+        // degenerate triangle
+        let positions = [
+            Vec3::ZERO,
+            Vec3::Z,
+            Vec3::X,
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(5.0, 0.0, 0.0),
+            Vec3::new(5.0, 0.0, 0.0),
+            Vec3::new(5.0, 0.0, 0.0),
+        ];
+        let indices = vec![0, 1, 2, 0, 2, 3, 4, 5, 6];
+
+        for mode in [NormalGeneration::default(), NormalGeneration::Smooth] {
+            let mesh = generate_normals(&vertices(&positions), &indices, mode);
+
+            for vertex in &mesh.vertices {
+                assert!(vertex.normal.is_normalized(), "{mode:?}: {}", vertex.normal);
+            }
+            // The degenerate neighbor doesn't bend the regular triangle
+            for i in &mesh.indices[..3] {
+                let normal = mesh.vertices[*i as usize].normal;
+                assert!(normal.abs_diff_eq(Vec3::Y, 1e-5), "{mode:?}: {normal}");
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::path::{Path, PathBuf};
+
+use glam::{Vec2, Vec3};
+use wgpu::naga::FastHashMap;
+
+use crate::{
+    dense_storage::DenseStorageIndex,
+    load_error::LoadError,
+    material::Material,
+    mesh::{Mesh, Vertex},
+    mesh_object::MeshObject,
+    mesh_processing::{self, NormalGeneration},
+    scene::Scene,
+    texture::ColorSpace,
+};
+
+/// Imports every model of an OBJ file into `scene` together with the materials of its MTL file
+///
+/// Each model becomes a mesh and a mesh object at the origin. Models without a material use a
+/// white matte material. Texture paths are resolved relative to the OBJ file. A missing or broken
+/// MTL file or texture is logged and skipped, only the geometry has to load. Returns the handles
+/// of the created mesh objects.
+pub fn import_obj(
+    scene: &mut Scene,
+    path: impl AsRef<Path>,
+) -> Result<Vec<DenseStorageIndex>, LoadError> {
+    let path = path.as_ref();
+    let (models, obj_materials) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS)?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    let obj_materials = obj_materials.unwrap_or_else(|error| {
+        log::warn!(
+            "failed to load the materials of {}, using the default material: {error}",
+            path.display()
+        );
+        Vec::new()
+    });
+
+    // Materials often share textures, so every file is only loaded once per color space. Failed
+    // loads are remembered too so they're only reported once.
+    let mut textures = FastHashMap::<(PathBuf, ColorSpace), Option<_>>::default();
+    let mut materials = Vec::new();
+    for obj_material in obj_materials {
+        let mut load_texture = |value: &str, color_space| {
+            let path = directory.join(texture_path(value));
+
+            *textures
+                .entry((path.clone(), color_space))
+                .or_insert_with(|| {
+                    scene
+                        .load_texture(&path, color_space)
+                        .inspect_err(|error| {
+                            log::warn!("skipping texture {}: {error}", path.display())
+                        })
+                        .ok()
+                })
+        };
+
+        let material = Material {
+            albedo_texture: obj_material
+                .diffuse_texture
+                .as_deref()
+                .and_then(|value| load_texture(value, ColorSpace::Srgb)),
+            emissive_texture: obj_material
+                .unknown_param
+                .get("map_Ke")
+                .and_then(|value| load_texture(value, ColorSpace::Srgb)),
+            // tobj reads `map_Bump` into `normal_texture`, `norm` is the PBR extension name
+            normal_texture: obj_material
+                .unknown_param
+                .get("norm")
+                .or(obj_material.normal_texture.as_ref())
+                .and_then(|value| load_texture(value, ColorSpace::Linear)),
+            ..convert_material(&obj_material)
+        };
+
+        materials.push(scene.insert_material(material));
+    }
+    let mut default_material = None;
+
+    let mut mesh_objects = Vec::new();
+    for model in &models {
+        let material = match model.mesh.material_id {
+            Some(i) if i < materials.len() => materials[i],
+            _ => *default_material.get_or_insert_with(|| {
+                scene.insert_material(Material {
+                    albedo: Vec3::ONE,
+                    ..Default::default()
+                })
+            }),
+        };
+
+        let mesh = scene.insert_mesh(convert_mesh(&model.mesh, scene.normal_generation()));
+        mesh_objects.push(scene.insert_mesh_object(MeshObject {
+            mesh,
+            material,
+            transform: Default::default(),
+        }));
+    }
+
+    Ok(mesh_objects)
+}
+
+/// Converts a mesh loaded with `tobj::GPU_LOAD_OPTIONS`, generating normals if it has none
+pub fn convert_mesh(mesh: &tobj::Mesh, normal_generation: NormalGeneration) -> Mesh {
+    let vertex_count = mesh.positions.len() / 3;
+    let has_normals = mesh.normals.len() == mesh.positions.len();
+    let has_uvs = mesh.texcoords.len() == vertex_count * 2;
+
+    let vertices: Vec<_> = (0..vertex_count)
+        .map(|i| Vertex {
+            pos: Vec3::from_slice(&mesh.positions[i * 3..]),
+            normal: if has_normals {
+                Vec3::from_slice(&mesh.normals[i * 3..])
+            } else {
+                Vec3::ZERO
+            },
+            // OBJ texture coordinates start at the bottom left, textures are stored top down
+            uv: if has_uvs {
+                Vec2::new(mesh.texcoords[i * 2], 1.0 - mesh.texcoords[i * 2 + 1])
+            } else {
+                Vec2::ZERO
+            },
+        })
+        .collect();
+
+    if !has_normals {
+        return mesh_processing::generate_normals(&vertices, &mesh.indices, normal_generation);
+    }
+
+    Mesh {
+        vertices,
+        indices: mesh.indices.clone(),
+    }
+}
+
+fn convert_material(material: &tobj::Material) -> Material {
+    // `Ke` isn't part of the original MTL spec, so tobj leaves it unparsed
+    let emissive = material
+        .unknown_param
+        .get("Ke")
+        .and_then(|value| parse_vec3(value))
+        .unwrap_or_default();
+
+    // Prefer the PBR extension parameters and fall back to converting the Phong exponent `Ns`
+    let pbr_param = |name| material.unknown_param.get(name)?.trim().parse::<f32>().ok();
+    let roughness = pbr_param("Pr")
+        .or_else(|| material.shininess.map(shininess_to_roughness))
+        .unwrap_or(1.0);
+
+    // Only the glass illumination models refract, `Tf` of other models is usually an exporter
+    // default. Dissolve (`d`) is the opacity of cut-outs rather than glass.
+    let is_glass = matches!(material.illumination_model, Some(4..=7));
+    let default_material = Material::default();
+
+    Material {
+        albedo: material.diffuse.map(Vec3::from).unwrap_or(Vec3::ONE),
+        emissive,
+        emissive_strength: 1.0,
+        metallic: pbr_param("Pm").unwrap_or(0.0),
+        roughness,
+        ior: material.optical_density.unwrap_or(default_material.ior),
+        transmission: if is_glass { 1.0 } else { 0.0 },
+        transmission_tint: material
+            .unknown_param
+            .get("Tf")
+            .filter(|_| is_glass)
+            .and_then(|value| parse_vec3(value))
+            .unwrap_or(Vec3::ONE),
+        opacity: material.dissolve.unwrap_or(1.0).clamp(0.0, 1.0),
+        ..default_material
+    }
+}
+
+/// Strips texture options like `-bm 1.0` and returns the file name, which is the last argument
+fn texture_path(value: &str) -> &str {
+    let value = value.trim();
+
+    if value.starts_with('-') {
+        value.split_whitespace().last().unwrap_or(value)
+    } else {
+        value
+    }
+}
+
+/// Converts a Blinn-Phong exponent to GGX roughness using `alpha = sqrt(2 / (n + 2))`
+fn shininess_to_roughness(shininess: f32) -> f32 {
+    let alpha = (2.0 / (shininess.max(0.0) + 2.0)).sqrt();
+
+    alpha.sqrt()
+}
+
+fn parse_vec3(value: &str) -> Option<Vec3> {
+    let mut components = value.split_whitespace().map(str::parse::<f32>);
+    let x = components.next()?.ok()?;
+
+    // A single value is used for all three channels
+    match (components.next(), components.next()) {
+        (Some(y), Some(z)) => Some(Vec3::new(x, y.ok()?, z.ok()?)),
+        _ => Some(Vec3::splat(x)),
+    }
+}
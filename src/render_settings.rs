@@ -0,0 +1,58 @@
+use glam::Vec3;
+
+use crate::{
+    aov::{Aov, AovSet},
+    denoiser::DenoiserSettings,
+    tone_mapping::ToneMapping,
+};
+
+/// Options that control how the scene is raytraced
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderSettings {
+    /// Rays traced per pixel in each frame
+    pub samples_per_pixel: u32,
+    /// The maximum number of times a ray can bounce before it's terminated
+    pub max_bounces: u32,
+    /// Intersections closer than this distance are ignored to avoid self intersections
+    pub min_distance: f32,
+    /// Intersections further away than this distance are ignored
+    pub max_distance: f32,
+    /// The color returned by rays that don't hit anything
+    pub sky_color: Vec3,
+    /// Offsets the random sequence, renders with the same seed are identical
+    pub seed: u32,
+    /// The maximum brightness of a single sample, `0.0` disables clamping
+    pub firefly_clamp: f32,
+    /// Renders at this fraction of the output size and upscales the result, `0.5` traces a
+    /// quarter of the rays per frame for faster interactive previews
+    pub resolution_scale: f32,
+    /// How the radiance is displayed, changing it doesn't restart accumulation
+    pub tone_mapping: ToneMapping,
+    /// The AOVs rendered next to the color, changing them restarts accumulation
+    pub aovs: AovSet,
+    /// Shows an AOV in the output instead of the color, it's rendered even if it's not in `aovs`
+    pub view_aov: Option<Aov>,
+    /// Filters the noise out of the color before it's shown. Switching the mode restarts
+    /// accumulation if the feature AOVs it needs aren't in `aovs`, since the AOV buffer is
+    /// recreated. Other changes keep the accumulated image.
+    pub denoiser: DenoiserSettings,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            samples_per_pixel: 100,
+            max_bounces: 10,
+            min_distance: 0.001,
+            max_distance: f32::MAX,
+            sky_color: Vec3::new(143.0, 210.0, 255.0) / 255.0,
+            seed: 0,
+            firefly_clamp: 0.0,
+            resolution_scale: 1.0,
+            tone_mapping: ToneMapping::default(),
+            aovs: AovSet::default(),
+            view_aov: None,
+            denoiser: DenoiserSettings::default(),
+        }
+    }
+}
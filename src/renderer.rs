@@ -0,0 +1,871 @@
+use std::num::NonZeroU32;
+
+use glam::{Mat4, Vec4};
+use winit::dpi::PhysicalSize;
+
+use crate::{
+    aov::{Aov, AovSet},
+    camera::{Bokeh, Projection},
+    denoiser::{Denoiser, DenoiserFrame, DenoiserMode},
+    render_settings::RenderSettings,
+    scene::Scene,
+    shader_types::{
+        GpuSky, GpuUniform, NO_AOV, PROJECTION_EQUIRECTANGULAR, PROJECTION_FISHEYE,
+        PROJECTION_ORTHOGRAPHIC, PROJECTION_PERSPECTIVE,
+    },
+};
+
+/// The format of the raytracing output texture
+pub const RT_TARGET_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// The device features the renderer can't run without
+pub const REQUIRED_FEATURES: wgpu::Features = wgpu::Features::TEXTURE_BINDING_ARRAY
+    .union(wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING)
+    .union(wgpu::Features::VERTEX_WRITABLE_STORAGE)
+    .union(wgpu::Features::EXPERIMENTAL_RAY_QUERY)
+    .union(wgpu::Features::EXPERIMENTAL_RAY_TRACING_ACCELERATION_STRUCTURE);
+
+/// Raytraces a scene into an offscreen texture, independent of any window or surface
+pub struct Renderer {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    size: PhysicalSize<u32>,
+    settings: RenderSettings,
+    /// The number of frames accumulated since the last reset
+    frame_index: u32,
+    /// The scene revision the accumulated frames were rendered with
+    scene_revision: u64,
+    /// The GPU resource revision of the scene `compute_bind_group` was created with
+    gpu_resources_revision: u64,
+    /// Incremented whenever the render targets are recreated
+    render_target_revision: u64,
+
+    rt_target: wgpu::Texture,
+    rt_view: wgpu::TextureView,
+    uniform_buffer: wgpu::Buffer,
+    accumulation_buffer: wgpu::Buffer,
+    /// The accumulated AOVs of `aovs`, one image after the other
+    aov_buffer: wgpu::Buffer,
+    /// The AOVs `aov_buffer` has room for
+    aovs: AovSet,
+    texture_sampler: wgpu::Sampler,
+    rt_compute_shader: wgpu::ShaderModule,
+    compute_pipeline: wgpu::ComputePipeline,
+    compute_bind_group_layout: wgpu::BindGroupLayout,
+    /// The size of the texture binding array in `compute_bind_group_layout`
+    texture_count: usize,
+    /// Created on the first frame and recreated whenever the scene replaces GPU resources
+    compute_bind_group: Option<wgpu::BindGroup>,
+    tlas_package: wgpu::TlasPackage,
+    denoiser: Denoiser,
+    scene: Scene,
+}
+
+impl Renderer {
+    pub async fn new(
+        adapter: &wgpu::Adapter,
+        size: PhysicalSize<u32>,
+        mut scene: Scene,
+    ) -> Renderer {
+        let (device, queue) = adapter
+            .request_device(&wgpu::DeviceDescriptor {
+                required_features: REQUIRED_FEATURES,
+                // The default limits don't allow binding arrays of material textures
+                required_limits: adapter.limits(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let settings = RenderSettings::default();
+        let (rt_target, rt_view, accumulation_buffer) =
+            create_render_targets(&device, render_size(size, settings.resolution_scale));
+        let aovs = active_aovs(&settings);
+        let aov_buffer = create_aov_buffer(&device, &rt_target, aovs);
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Uniform Buffer"),
+            size: std::mem::size_of::<GpuUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let texture_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Material texture sampler"),
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let gpu_scene = scene.get_or_upload_gpu_scene(&device, &queue);
+
+        // Binding arrays need an explicit layout since derived layouts can't size them
+        let compute_bind_group_layout =
+            create_compute_bind_group_layout(&device, gpu_scene.texture_views.len() as u32);
+
+        let rt_compute_shader =
+            device.create_shader_module(wgpu::include_wgsl!("shaders/rt_compute.wgsl"));
+        let compute_pipeline =
+            create_compute_pipeline(&device, &rt_compute_shader, &compute_bind_group_layout);
+        let texture_count = gpu_scene.texture_views.len();
+
+        let instance_count = gpu_scene
+            .instance_transforms
+            .iter()
+            .map(|transforms| transforms.len() as u32)
+            .sum::<u32>();
+        let tlas_package = create_tlas_package(&device, instance_count);
+        let denoiser = Denoiser::new(&device);
+
+        Renderer {
+            scene_revision: scene.revision(),
+            gpu_resources_revision: scene.gpu_resources_revision(),
+            render_target_revision: 0,
+            device,
+            queue,
+            size,
+            settings,
+            frame_index: 0,
+            rt_target,
+            rt_view,
+            uniform_buffer,
+            accumulation_buffer,
+            aov_buffer,
+            aovs,
+            texture_sampler,
+            rt_compute_shader,
+            compute_pipeline,
+            compute_bind_group_layout,
+            texture_count,
+            compute_bind_group: None,
+            tlas_package,
+            denoiser,
+            scene,
+        }
+    }
+
+    fn create_compute_bind_group(&self) -> wgpu::BindGroup {
+        let gpu_scene = self
+            .scene
+            .gpu_scene()
+            .expect("the scene should be uploaded before creating bind groups");
+
+        self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.compute_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&self.rt_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: self.uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: gpu_scene.vertex_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: gpu_scene.index_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: gpu_scene.material_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: gpu_scene.instance_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: self.tlas_package.as_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: self.accumulation_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: wgpu::BindingResource::TextureViewArray(
+                        &gpu_scene.texture_views.iter().collect::<Vec<_>>(),
+                    ),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: wgpu::BindingResource::Sampler(&self.texture_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: wgpu::BindingResource::TextureView(&gpu_scene.environment_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 11,
+                    resource: gpu_scene
+                        .environment_distribution_buffer
+                        .as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 12,
+                    resource: gpu_scene.lights.light_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 13,
+                    resource: gpu_scene
+                        .lights
+                        .emissive_triangle_buffer
+                        .as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 14,
+                    resource: gpu_scene.lights.instance_emitter_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 15,
+                    resource: gpu_scene.lights.light_bvh_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 16,
+                    resource: self.aov_buffer.as_entire_binding(),
+                },
+            ],
+        })
+    }
+
+    /// Returns the `PROJECTION_*` constant, the projection matrix and the fisheye field of view of
+    /// the camera
+    fn projection(&self) -> (u32, Mat4, f32) {
+        let camera = self.scene.camera();
+        let aspect_ratio = self.rt_target.width() as f32 / self.rt_target.height() as f32;
+        // Panoramas and fisheyes compute their rays without a projection matrix
+        match camera.projection {
+            Projection::Perspective => (
+                PROJECTION_PERSPECTIVE,
+                Mat4::perspective_rh(
+                    camera.fov.to_radians(),
+                    aspect_ratio,
+                    camera.near_clip,
+                    camera.far_clip,
+                ),
+                0.0,
+            ),
+            Projection::Orthographic { view_height } => {
+                let half_height = view_height * 0.5;
+                let half_width = half_height * aspect_ratio;
+                (
+                    PROJECTION_ORTHOGRAPHIC,
+                    Mat4::orthographic_rh(
+                        -half_width,
+                        half_width,
+                        -half_height,
+                        half_height,
+                        camera.near_clip,
+                        camera.far_clip,
+                    ),
+                    0.0,
+                )
+            }
+            Projection::Equirectangular => (PROJECTION_EQUIRECTANGULAR, Mat4::IDENTITY, 0.0),
+            Projection::Fisheye { fov } => (PROJECTION_FISHEYE, Mat4::IDENTITY, fov.to_radians()),
+        }
+    }
+
+    fn write_uniform(&self) {
+        let camera = self.scene.camera();
+        let environment = self.scene.environment();
+        let sky = self.scene.sky();
+        let gpu_scene = self.scene.gpu_scene();
+
+        let (projection, proj, fisheye_fov) = self.projection();
+
+        let (bokeh_blades, bokeh_rotation) = match camera.bokeh {
+            Bokeh::Circle => (0, 0.0),
+            Bokeh::Blades { count, rotation } => (count.max(3), rotation),
+        };
+
+        let gpu_uniform = GpuUniform {
+            // The camera transform maps camera space to world space
+            view_inverse: Mat4::from(camera.transform),
+            proj_inverse: proj.inverse(),
+            sky_color: self.settings.sky_color,
+            samples_per_pixel: self.settings.samples_per_pixel,
+            frame_index: self.frame_index,
+            max_bounces: self.settings.max_bounces,
+            seed: self.settings.seed,
+            firefly_clamp: self.settings.firefly_clamp,
+            min_distance: self.settings.min_distance,
+            max_distance: self.settings.max_distance,
+            environment_rotation: environment.map_or(0.0, |environment| environment.rotation),
+            environment_intensity: environment.map_or(0.0, |environment| environment.intensity),
+            use_environment: environment.is_some() as u32,
+            directional_light_count: gpu_scene
+                .map_or(0, |gpu_scene| gpu_scene.lights.directional_light_count),
+            light_bvh_node_count: gpu_scene
+                .map_or(0, |gpu_scene| gpu_scene.lights.light_bvh_node_count),
+            use_sky: sky.is_some() as u32,
+            sky: sky.map(GpuSky::from).unwrap_or_default(),
+            aperture_radius: camera.aperture_radius,
+            focus_distance: camera.focus_distance,
+            bokeh_blades,
+            bokeh_rotation,
+            projection,
+            fisheye_fov,
+            aov_mask: self.aovs.bits(),
+            view_aov: self.settings.view_aov.map_or(NO_AOV, Aov::index),
+        };
+        self.queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[gpu_uniform]),
+        );
+    }
+
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    /// Replaces the render settings
+    ///
+    /// The accumulated image is kept if only the samples per pixel or the way it's displayed
+    /// changed since it stays valid
+    pub fn set_settings(&mut self, settings: RenderSettings) {
+        let previous = std::mem::replace(&mut self.settings, settings);
+        // Tone mapping and denoising are applied when the accumulated radiance is displayed, viewed
+        // AOVs are accumulated as long as they are in `aovs`
+        let only_display_changed = RenderSettings {
+            samples_per_pixel: settings.samples_per_pixel,
+            tone_mapping: settings.tone_mapping,
+            aovs: settings.aovs,
+            view_aov: settings.view_aov,
+            denoiser: settings.denoiser,
+            ..previous
+        } == settings;
+
+        if !only_display_changed {
+            self.reset_accumulation();
+        }
+        if settings.resolution_scale != previous.resolution_scale {
+            self.recreate_render_targets();
+        }
+        if active_aovs(&settings) != self.aovs {
+            self.recreate_aov_buffer();
+        }
+        if settings.denoiser.mode != previous.denoiser.mode {
+            self.denoiser.invalidate();
+        }
+    }
+
+    /// Changes the output size, the render targets are recreated to match it
+    pub fn resize(&mut self, new_size: PhysicalSize<u32>) {
+        self.size = new_size;
+
+        self.recreate_render_targets();
+        self.reset_accumulation();
+    }
+
+    fn recreate_render_targets(&mut self) {
+        let size = render_size(self.size, self.settings.resolution_scale);
+        if size.width == self.rt_target.width() && size.height == self.rt_target.height() {
+            return;
+        }
+
+        (self.rt_target, self.rt_view, self.accumulation_buffer) =
+            create_render_targets(&self.device, size);
+        // Both the compute bind group and the blit bind group of the window use the old targets
+        self.compute_bind_group = None;
+        self.render_target_revision += 1;
+        self.recreate_aov_buffer();
+        self.reset_accumulation();
+    }
+
+    /// Makes room for the AOVs of the current settings at the current size
+    fn recreate_aov_buffer(&mut self) {
+        self.aovs = active_aovs(&self.settings);
+        self.aov_buffer = create_aov_buffer(&self.device, &self.rt_target, self.aovs);
+        self.compute_bind_group = None;
+        self.denoiser.invalidate();
+        self.reset_accumulation();
+    }
+
+    /// Returns a counter that changes every time `rt_view()` is replaced
+    pub fn render_target_revision(&self) -> u64 {
+        self.render_target_revision
+    }
+
+    /// Discards the accumulated samples so the next frame starts a new image
+    pub fn reset_accumulation(&mut self) {
+        self.frame_index = 0;
+    }
+
+    pub fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    /// Returns the scene for editing, accumulation restarts once a change is detected
+    pub fn scene_mut(&mut self) -> &mut Scene {
+        &mut self.scene
+    }
+
+    /// Records the acceleration structure update, the raytracing pass and the denoiser into
+    /// `encoder`
+    pub fn encode(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if self.scene.revision() != self.scene_revision {
+            self.scene_revision = self.scene.revision();
+            self.reset_accumulation();
+        }
+
+        // Instances are written every frame so moved mesh objects show up without an upload
+        let gpu_scene = self
+            .scene
+            .get_or_upload_gpu_scene(&self.device, &self.queue);
+
+        let instance_count = gpu_scene
+            .instance_transforms
+            .iter()
+            .map(Vec::len)
+            .sum::<usize>();
+        if instance_count > self.tlas_package.get().len() {
+            self.tlas_package = create_tlas_package(&self.device, instance_count as u32);
+            // The bind group references the old TLAS
+            self.compute_bind_group = None;
+        }
+
+        let mut tlas_i = 0usize;
+        for (instance_i, (blas, transforms)) in gpu_scene
+            .bottom_level_acceleration_structures
+            .iter()
+            .zip(gpu_scene.instance_transforms.iter())
+            .enumerate()
+        {
+            for transform in transforms {
+                self.tlas_package[tlas_i] = Some(wgpu::TlasInstance::new(
+                    blas,
+                    Mat4::from(transform).transpose().to_cols_array()[..12]
+                        .try_into()
+                        .unwrap(),
+                    instance_i as u32,
+                    0xff,
+                ));
+
+                tlas_i += 1;
+            }
+        }
+        // Clears instances of mesh objects that were removed since the last frame
+        for instance in tlas_i..self.tlas_package.get().len() {
+            self.tlas_package[instance] = None;
+        }
+
+        // Written after the upload so the uniforms see the current light count
+        self.write_uniform();
+        self.frame_index += 1;
+
+        encoder
+            .build_acceleration_structures(std::iter::empty(), std::iter::once(&self.tlas_package));
+
+        if self.compute_bind_group.is_none()
+            || self.scene.gpu_resources_revision() != self.gpu_resources_revision
+        {
+            self.gpu_resources_revision = self.scene.gpu_resources_revision();
+
+            // The texture binding array is sized in the layout, so the pipeline has to follow it
+            let texture_count = self
+                .scene
+                .gpu_scene()
+                .map_or(0, |gpu_scene| gpu_scene.texture_views.len());
+            if texture_count != self.texture_count {
+                self.compute_bind_group_layout =
+                    create_compute_bind_group_layout(&self.device, texture_count as u32);
+                self.compute_pipeline = create_compute_pipeline(
+                    &self.device,
+                    &self.rt_compute_shader,
+                    &self.compute_bind_group_layout,
+                );
+                self.texture_count = texture_count;
+            }
+
+            self.compute_bind_group = Some(self.create_compute_bind_group());
+        }
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: None,
+            timestamp_writes: None,
+        });
+
+        compute_pass.set_pipeline(&self.compute_pipeline);
+        compute_pass.set_bind_group(0, self.compute_bind_group.as_ref(), &[]);
+        // Rounded up so partial tiles at the edges are rendered too, the shader skips the pixels
+        // outside the image
+        compute_pass.dispatch_workgroups(
+            self.rt_target.width().div_ceil(8),
+            self.rt_target.height().div_ceil(8),
+            1,
+        );
+        drop(compute_pass);
+
+        // Viewed AOVs are shown as they are
+        if self.settings.denoiser.mode != DenoiserMode::Off && self.settings.view_aov.is_none() {
+            let (projection, proj, _) = self.projection();
+            let view_projection =
+                matches!(projection, PROJECTION_PERSPECTIVE | PROJECTION_ORTHOGRAPHIC)
+                    .then(|| proj * self.scene.camera().view_matrix());
+
+            self.denoiser.encode(
+                &self.device,
+                &self.queue,
+                encoder,
+                &DenoiserFrame {
+                    rt_target: &self.rt_target,
+                    rt_view: &self.rt_view,
+                    aov_buffer: &self.aov_buffer,
+                    aovs: self.aovs,
+                    settings: &self.settings.denoiser,
+                    view_projection,
+                    accumulated_frames: self.frame_index,
+                },
+            );
+        }
+    }
+
+    /// Renders a single frame without presenting it anywhere
+    pub fn render(&mut self) {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+
+        self.encode(&mut encoder);
+
+        self.queue.submit([encoder.finish()]);
+    }
+
+    /// Copies the raytracing target back to the CPU, blocking until the copy is finished
+    pub fn read_target(&self) -> Vec<Vec4> {
+        let width = self.rt_target.width();
+        let height = self.rt_target.height();
+        let bytes_per_pixel = RT_TARGET_FORMAT.block_copy_size(None).unwrap();
+        let bytes_per_row =
+            (width * bytes_per_pixel).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+
+        let readback_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Readback"),
+            size: bytes_per_row as u64 * height as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        encoder.copy_texture_to_buffer(
+            self.rt_target.as_image_copy(),
+            wgpu::TexelCopyBufferInfo {
+                buffer: &readback_buffer,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            self.rt_target.size(),
+        );
+        self.queue.submit([encoder.finish()]);
+
+        let buffer_slice = readback_buffer.slice(..);
+        buffer_slice.map_async(wgpu::MapMode::Read, |result| {
+            result.expect("failed to map the readback buffer");
+        });
+        self.device
+            .poll(wgpu::PollType::Wait)
+            .expect("failed to wait for the readback");
+
+        let data = buffer_slice.get_mapped_range();
+        let pixels = data
+            .chunks_exact(bytes_per_row as usize)
+            .flat_map(|row| {
+                row[..(width * bytes_per_pixel) as usize]
+                    .chunks_exact(bytes_per_pixel as usize)
+                    .map(|texel| {
+                        let channel = |i: usize| {
+                            half::f16::from_le_bytes([texel[i * 2], texel[i * 2 + 1]]).to_f32()
+                        };
+                        Vec4::new(channel(0), channel(1), channel(2), channel(3))
+                    })
+            })
+            .collect();
+
+        drop(data);
+        readback_buffer.unmap();
+
+        pixels
+    }
+
+    /// Copies the averaged AOVs back to the CPU, blocking until the copy is finished
+    pub fn read_aovs(&self) -> Vec<(Aov, Vec<Vec4>)> {
+        let pixel_count = (self.rt_target.width() * self.rt_target.height()) as usize;
+
+        let readback_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("AOV Readback"),
+            size: self.aov_buffer.size(),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        encoder.copy_buffer_to_buffer(
+            &self.aov_buffer,
+            0,
+            &readback_buffer,
+            0,
+            self.aov_buffer.size(),
+        );
+        self.queue.submit([encoder.finish()]);
+
+        let buffer_slice = readback_buffer.slice(..);
+        buffer_slice.map_async(wgpu::MapMode::Read, |result| {
+            result.expect("failed to map the readback buffer");
+        });
+        self.device
+            .poll(wgpu::PollType::Wait)
+            .expect("failed to wait for the readback");
+
+        let data = buffer_slice.get_mapped_range();
+        // Mapped buffers aren't guaranteed to be aligned enough for `Vec4`
+        let floats: &[f32] = bytemuck::cast_slice(&data);
+        let aovs = self
+            .aovs
+            .iter()
+            .zip(floats.chunks_exact(pixel_count * 4))
+            .map(|(aov, floats)| {
+                // The sample count is in `w`
+                let pixels = floats
+                    .chunks_exact(4)
+                    .map(Vec4::from_slice)
+                    .map(|sum| (sum.truncate() / sum.w.max(1.0)).extend(1.0))
+                    .collect();
+                (aov, pixels)
+            })
+            .collect();
+
+        drop(data);
+        readback_buffer.unmap();
+
+        aovs
+    }
+
+    pub fn rt_view(&self) -> &wgpu::TextureView {
+        &self.rt_view
+    }
+
+    pub fn device(&self) -> &wgpu::Device {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &wgpu::Queue {
+        &self.queue
+    }
+}
+
+fn create_compute_bind_group_layout(
+    device: &wgpu::Device,
+    texture_count: u32,
+) -> wgpu::BindGroupLayout {
+    let storage_buffer = |binding, read_only| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("rt"),
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: RT_TARGET_FORMAT,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            storage_buffer(2, true),
+            storage_buffer(3, true),
+            storage_buffer(4, true),
+            storage_buffer(5, true),
+            wgpu::BindGroupLayoutEntry {
+                binding: 6,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::AccelerationStructure {
+                    vertex_return: false,
+                },
+                count: None,
+            },
+            storage_buffer(7, false),
+            wgpu::BindGroupLayoutEntry {
+                binding: 8,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: NonZeroU32::new(texture_count),
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 9,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 10,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Texture {
+                    // 32-bit float textures can't be filtered without an extra feature
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            storage_buffer(11, true),
+            storage_buffer(12, true),
+            storage_buffer(13, true),
+            storage_buffer(14, true),
+            storage_buffer(15, true),
+            storage_buffer(16, false),
+        ],
+    })
+}
+
+/// The size of the raytraced image for an output size and a resolution scale
+fn render_size(size: PhysicalSize<u32>, resolution_scale: f32) -> PhysicalSize<u32> {
+    let scale = |length: u32| ((length as f32 * resolution_scale).round() as u32).max(1);
+
+    PhysicalSize::new(scale(size.width), scale(size.height))
+}
+
+/// Creates the raytraced image and the accumulation buffer behind it
+fn create_render_targets(
+    device: &wgpu::Device,
+    size: PhysicalSize<u32>,
+) -> (wgpu::Texture, wgpu::TextureView, wgpu::Buffer) {
+    let rt_target = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("rt_target"),
+        size: wgpu::Extent3d {
+            width: size.width,
+            height: size.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: RT_TARGET_FORMAT,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::STORAGE_BINDING
+            | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[RT_TARGET_FORMAT],
+    });
+
+    let rt_view = rt_target.create_view(&wgpu::TextureViewDescriptor {
+        label: None,
+        format: Some(RT_TARGET_FORMAT),
+        dimension: Some(wgpu::TextureViewDimension::D2),
+        usage: None,
+        aspect: wgpu::TextureAspect::All,
+        base_mip_level: 0,
+        mip_level_count: None,
+        base_array_layer: 0,
+        array_layer_count: None,
+    });
+
+    // Holds the running sum of radiance in `xyz` and the number of samples in `w`
+    let accumulation_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Accumulation Buffer"),
+        size: (rt_target.width() * rt_target.height()) as u64 * std::mem::size_of::<Vec4>() as u64,
+        usage: wgpu::BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    });
+
+    (rt_target, rt_view, accumulation_buffer)
+}
+
+/// The AOVs that have to be rendered for `settings`
+fn active_aovs(settings: &RenderSettings) -> AovSet {
+    let aovs = settings.aovs.union(settings.denoiser.mode.required_aovs());
+
+    match settings.view_aov {
+        Some(aov) => aovs.with(aov),
+        None => aovs,
+    }
+}
+
+/// Creates the accumulation buffer of the AOVs, with an image the size of `rt_target` for every
+/// AOV in `aovs`
+fn create_aov_buffer(
+    device: &wgpu::Device,
+    rt_target: &wgpu::Texture,
+    aovs: AovSet,
+) -> wgpu::Buffer {
+    let pixel_count = (rt_target.width() * rt_target.height()) as u64;
+
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("AOV Buffer"),
+        // Bindings can't be empty, so there is always room for one image
+        size: aovs.len().max(1) as u64 * pixel_count * std::mem::size_of::<Vec4>() as u64,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_compute_pipeline(
+    device: &wgpu::Device,
+    shader: &wgpu::ShaderModule,
+    bind_group_layout: &wgpu::BindGroupLayout,
+) -> wgpu::ComputePipeline {
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("rt"),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("rt"),
+        layout: Some(&layout),
+        module: shader,
+        entry_point: Some("main"),
+        compilation_options: Default::default(),
+        cache: None,
+    })
+}
+
+/// Creates a TLAS with room for `max_instances` instances
+fn create_tlas_package(device: &wgpu::Device, max_instances: u32) -> wgpu::TlasPackage {
+    let tlas = device.create_tlas(&wgpu::CreateTlasDescriptor {
+        label: None,
+        flags: wgpu::AccelerationStructureFlags::PREFER_FAST_TRACE,
+        update_mode: wgpu::AccelerationStructureUpdateMode::Build,
+        max_instances: max_instances.max(1),
+    });
+
+    wgpu::TlasPackage::new(tlas)
+}
@@ -0,0 +1,50 @@
+use std::{fmt, io};
+
+/// An error that occurred while saving a rendered image
+#[derive(Debug)]
+pub enum SaveError {
+    /// The image couldn't be encoded or written by the `image` crate
+    Image(image::ImageError),
+    /// The OpenEXR file couldn't be written
+    Exr(exr::error::Error),
+    /// The file couldn't be written
+    Io(io::Error),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Image(error) => write!(f, "failed to save image: {error}"),
+            SaveError::Exr(error) => write!(f, "failed to save OpenEXR file: {error}"),
+            SaveError::Io(error) => write!(f, "failed to write file: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SaveError::Image(error) => Some(error),
+            SaveError::Exr(error) => Some(error),
+            SaveError::Io(error) => Some(error),
+        }
+    }
+}
+
+impl From<image::ImageError> for SaveError {
+    fn from(value: image::ImageError) -> Self {
+        Self::Image(value)
+    }
+}
+
+impl From<exr::error::Error> for SaveError {
+    fn from(value: exr::error::Error) -> Self {
+        Self::Exr(value)
+    }
+}
+
+impl From<io::Error> for SaveError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
@@ -1,136 +1,784 @@
-use glam::{Mat4, Vec3};
+use std::path::Path;
+
+use glam::{Mat4, Quat, Vec3, Vec4};
 use wgpu::{naga::FastHashMap, util::DeviceExt};
-use winit::dpi::PhysicalSize;
 
 use crate::{
     camera::Camera,
     dense_storage::{DenseStorage, DenseStorageIndex},
+    environment::Environment,
+    gltf_import,
+    light::{Light, LightShape},
+    load_error::LoadError,
     material::Material,
-    mesh::{Mesh, Vertex},
+    mesh::Mesh,
     mesh_object::MeshObject,
-    shader_types::{GpuInstance, GpuMaterial, GpuUniform, GpuVertex},
+    mesh_processing::NormalGeneration,
+    obj_import,
+    shader_types::{
+        GpuEmissiveTriangle, GpuInstance, GpuLight, GpuLightBvhNode, GpuMaterial, GpuVertex,
+        LIGHT_BVH_LEAF, LIGHT_BVH_TRIANGLE, LIGHT_BVH_TWO_SIDED, NO_EMITTER, NO_TEXTURE,
+    },
+    sky::Sky,
+    texture::{ColorSpace, Texture},
     transform::Transform,
 };
 
-/// A scene that contains mesh objects and their meshes/materials
+/// A scene that contains mesh objects and their meshes/materials/textures, and lights
 #[derive(Debug, Default, Clone)]
 pub struct Scene {
     meshes: DenseStorage<Mesh>,
     materials: DenseStorage<Material>,
+    textures: DenseStorage<Texture>,
     mesh_objects: DenseStorage<MeshObject>,
+    lights: DenseStorage<Light>,
     camera: Camera,
+    environment: Option<Environment>,
+    sky: Option<Sky>,
+    normal_generation: NormalGeneration,
+    /// Incremented whenever a change affects the rendered image
+    revision: u64,
+    /// Incremented whenever GPU resources are recreated
+    gpu_resources_revision: u64,
+    materials_dirty: bool,
+    lights_dirty: bool,
+    environment_dirty: bool,
+    mesh_objects_dirty: bool,
+    /// Meshes, materials or textures were inserted or removed since the last upload
+    resources_dirty: bool,
     gpu_scene: Option<GpuScene>,
 }
 
 impl Scene {
-    /// Loads a mesh and returns a handle if successful
-    pub fn load_mesh(&mut self, path: &str) -> Option<DenseStorageIndex> {
-        let (models, _) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS).ok()?;
-
-        let model = models.first()?;
-
-        Some(
-            self.meshes.push(Mesh {
-                vertices: model
-                    .mesh
-                    .positions
-                    .chunks_exact(3)
-                    .zip(model.mesh.normals.chunks_exact(3))
-                    .map(|(pos, normal)| Vertex {
-                        pos: Vec3::from_slice(pos),
-                        normal: Vec3::from_slice(normal),
-                    })
-                    .collect(),
-                indices: model.mesh.indices.clone(),
-            }),
-        )
+    /// Loads the first model of an OBJ file as a mesh and returns a handle
+    pub fn load_mesh(&mut self, path: &str) -> Result<DenseStorageIndex, LoadError> {
+        let (models, _) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS)?;
+
+        let model = models.first().ok_or(LoadError::NoMeshes)?;
+        let mesh = obj_import::convert_mesh(&model.mesh, self.normal_generation);
+
+        Ok(self.insert_mesh(mesh))
+    }
+
+    /// Loads every model of an OBJ file as a mesh object, using the materials from its MTL file
+    ///
+    /// Returns the handles of the created mesh objects
+    pub fn load_obj(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<DenseStorageIndex>, LoadError> {
+        obj_import::import_obj(self, path)
+    }
+
+    /// Loads the meshes, materials, mesh objects and camera of a glTF 2.0 or GLB file
+    ///
+    /// Returns the handles of the created mesh objects
+    pub fn load_gltf(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<DenseStorageIndex>, LoadError> {
+        gltf_import::import_gltf(self, path)
+    }
+
+    /// Returns how normals are generated for loaded meshes that don't have any
+    pub fn normal_generation(&self) -> NormalGeneration {
+        self.normal_generation
+    }
+
+    /// Sets how normals are generated for meshes loaded after this call that don't have any
+    #[allow(unused)]
+    pub fn set_normal_generation(&mut self, normal_generation: NormalGeneration) {
+        self.normal_generation = normal_generation;
+    }
+
+    /// Inserts a mesh and returns a handle
+    pub fn insert_mesh(&mut self, mesh: Mesh) -> DenseStorageIndex {
+        self.revision += 1;
+        self.resources_dirty = true;
+
+        self.meshes.push(mesh)
+    }
+
+    /// Returns a mesh if the handle is still valid
+    pub fn get_mesh(&self, handle: DenseStorageIndex) -> Option<&Mesh> {
+        self.meshes.get(handle)
+    }
+
+    /// Removes a mesh, returning it if the handle was still valid
+    ///
+    /// Mesh objects that use it are skipped until they are removed or pointed at another mesh.
+    #[allow(unused)]
+    pub fn remove_mesh(&mut self, handle: DenseStorageIndex) -> Option<Mesh> {
+        let mesh = self.meshes.remove(handle)?;
+
+        self.revision += 1;
+        self.resources_dirty = true;
+
+        Some(mesh)
     }
 
     /// Inserts a material and returns a handle
     pub fn insert_material(&mut self, material: Material) -> DenseStorageIndex {
+        self.revision += 1;
+        self.resources_dirty = true;
+
         self.materials.push(material)
     }
 
+    /// Removes a material, returning it if the handle was still valid
+    ///
+    /// Mesh objects that use it are skipped until they are removed.
+    #[allow(unused)]
+    pub fn remove_material(&mut self, handle: DenseStorageIndex) -> Option<Material> {
+        let material = self.materials.remove(handle)?;
+
+        self.revision += 1;
+        self.resources_dirty = true;
+
+        Some(material)
+    }
+
+    /// Returns a mutable reference to a material, the changes are uploaded with the next frame
+    #[allow(unused)]
+    pub fn get_material_mut(&mut self, handle: DenseStorageIndex) -> Option<&mut Material> {
+        let material = self.materials.get_mut(handle)?;
+
+        self.revision += 1;
+        self.materials_dirty = true;
+
+        Some(material)
+    }
+
+    /// Inserts a texture and returns a handle that materials can reference
+    pub fn insert_texture(&mut self, texture: Texture) -> DenseStorageIndex {
+        self.revision += 1;
+        self.resources_dirty = true;
+
+        self.textures.push(texture)
+    }
+
+    /// Removes a texture, returning it if the handle was still valid
+    ///
+    /// Materials that reference it are rendered as if they had no texture in that slot.
+    #[allow(unused)]
+    pub fn remove_texture(&mut self, handle: DenseStorageIndex) -> Option<Texture> {
+        let texture = self.textures.remove(handle)?;
+
+        self.revision += 1;
+        self.resources_dirty = true;
+
+        Some(texture)
+    }
+
+    /// Loads a PNG or JPEG image as a texture and returns a handle
+    #[allow(unused)]
+    pub fn load_texture(
+        &mut self,
+        path: impl AsRef<Path>,
+        color_space: ColorSpace,
+    ) -> Result<DenseStorageIndex, LoadError> {
+        let texture = Texture::load(path, color_space)?;
+
+        Ok(self.insert_texture(texture))
+    }
+
     /// Inserts a mesh object and returns a handle
     pub fn insert_mesh_object(&mut self, mesh_object: MeshObject) -> DenseStorageIndex {
+        self.revision += 1;
+        self.mesh_objects_dirty = true;
+
         self.mesh_objects.push(mesh_object)
     }
 
-    pub fn update_camera_size(&self, queue: &wgpu::Queue, size: PhysicalSize<u32>) {
-        let Some(gpu_scene) = &self.gpu_scene else {
-            return;
-        };
+    /// Removes a mesh object, returning it if the handle was still valid
+    #[allow(unused)]
+    pub fn remove_mesh_object(&mut self, handle: DenseStorageIndex) -> Option<MeshObject> {
+        let mesh_object = self.mesh_objects.remove(handle)?;
 
-        let view = Mat4::from(self.camera.transform);
-        let proj = Mat4::perspective_rh(
-            self.camera.fov.to_radians(),
-            size.width as f32 / size.height as f32,
-            self.camera.near_clip,
-            self.camera.far_clip,
-        );
+        self.revision += 1;
+        self.mesh_objects_dirty = true;
 
-        let gpu_uniform = GpuUniform {
-            view_inverse: view.inverse(),
-            proj_inverse: proj.inverse(),
+        Some(mesh_object)
+    }
+
+    /// Returns a mutable reference to a mesh object, the changes are uploaded with the next frame
+    ///
+    /// Transform changes only update the acceleration structure, a different mesh or material
+    /// uploads the scene again.
+    pub fn get_mesh_object_mut(&mut self, handle: DenseStorageIndex) -> Option<&mut MeshObject> {
+        let mesh_object = self.mesh_objects.get_mut(handle)?;
+
+        self.revision += 1;
+        self.mesh_objects_dirty = true;
+
+        Some(mesh_object)
+    }
+
+    /// Moves a mesh object without uploading its mesh again, returns `false` if the handle is no
+    /// longer valid
+    #[allow(unused)]
+    pub fn set_transform(&mut self, handle: DenseStorageIndex, transform: Transform) -> bool {
+        let Some(mesh_object) = self.get_mesh_object_mut(handle) else {
+            return false;
         };
-        queue.write_buffer(
-            &gpu_scene.uniform_buffer,
-            0,
-            bytemuck::cast_slice(&[gpu_uniform]),
-        );
+
+        mesh_object.transform = transform;
+        true
+    }
+
+    /// Inserts a light and returns a handle
+    #[allow(unused)]
+    pub fn insert_light(&mut self, light: Light) -> DenseStorageIndex {
+        self.revision += 1;
+        self.lights_dirty = true;
+
+        self.lights.push(light)
+    }
+
+    /// Returns a mutable reference to a light, the changes are uploaded with the next frame
+    #[allow(unused)]
+    pub fn get_light_mut(&mut self, handle: DenseStorageIndex) -> Option<&mut Light> {
+        let light = self.lights.get_mut(handle)?;
+
+        self.revision += 1;
+        self.lights_dirty = true;
+
+        Some(light)
+    }
+
+    /// Removes a light, returning it if the handle was still valid
+    #[allow(unused)]
+    pub fn remove_light(&mut self, handle: DenseStorageIndex) -> Option<Light> {
+        let light = self.lights.remove(handle)?;
+
+        self.revision += 1;
+        self.lights_dirty = true;
+
+        Some(light)
+    }
+
+    /// Returns the camera the scene is rendered from
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    /// Returns a mutable reference to the camera
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        self.revision += 1;
+
+        &mut self.camera
+    }
+
+    /// Returns the image that lights the scene from infinitely far away, if there is one
+    pub fn environment(&self) -> Option<&Environment> {
+        self.environment.as_ref()
+    }
+
+    /// Loads an equirectangular Radiance `.hdr` image that lights the scene and is seen by rays
+    /// that miss all geometry
+    ///
+    /// `rotation` turns the image counterclockwise around +Y in radians and `intensity` scales its
+    /// radiance. Replaces the physical sky and the sky color of the render settings.
+    #[allow(unused)]
+    pub fn set_environment(
+        &mut self,
+        path: impl AsRef<Path>,
+        rotation: f32,
+        intensity: f32,
+    ) -> Result<(), LoadError> {
+        let environment = Environment::load(path)?;
+
+        self.environment = Some(Environment {
+            rotation,
+            intensity,
+            ..environment
+        });
+        self.revision += 1;
+        self.environment_dirty = true;
+        if self.sky.take().is_some() {
+            self.lights_dirty = true;
+        }
+
+        Ok(())
+    }
+
+    /// Changes the rotation of the environment around +Y in radians, without reuploading it
+    #[allow(unused)]
+    pub fn set_environment_rotation(&mut self, rotation: f32) {
+        if let Some(environment) = &mut self.environment {
+            environment.rotation = rotation;
+            self.revision += 1;
+        }
+    }
+
+    /// Changes the radiance scale of the environment, without reuploading it
+    #[allow(unused)]
+    pub fn set_environment_intensity(&mut self, intensity: f32) {
+        if let Some(environment) = &mut self.environment {
+            environment.intensity = intensity;
+            self.revision += 1;
+        }
+    }
+
+    /// Removes the environment so rays that miss all geometry see the sky color again
+    #[allow(unused)]
+    pub fn remove_environment(&mut self) {
+        self.environment = None;
+        self.revision += 1;
+        self.environment_dirty = true;
+    }
+
+    /// Returns the physical sky that lights the scene, if there is one
+    pub fn sky(&self) -> Option<&Sky> {
+        self.sky.as_ref()
+    }
+
+    /// Lights the scene with a physical sky and its sun, which rays that miss all geometry see
+    ///
+    /// Replaces the environment and the sky color of the render settings. The sun is added to
+    /// the directional lights.
+    #[allow(unused)]
+    pub fn set_sky(&mut self, sky: Sky) {
+        if self.environment.take().is_some() {
+            self.environment_dirty = true;
+        }
+        self.sky = Some(sky);
+        self.revision += 1;
+        self.lights_dirty = true;
+    }
+
+    /// Removes the physical sky and its sun so rays that miss all geometry see the sky color
+    /// again
+    #[allow(unused)]
+    pub fn remove_sky(&mut self) {
+        if self.sky.take().is_some() {
+            self.revision += 1;
+            self.lights_dirty = true;
+        }
+    }
+
+    /// Returns a counter that changes every time the scene is modified
+    ///
+    /// Renderers compare it between frames to know when accumulated samples are stale
+    pub fn revision(&self) -> u64 {
+        self.revision
     }
 
     pub fn get_or_upload_gpu_scene(
         &mut self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        size: PhysicalSize<u32>,
     ) -> &GpuScene {
-        if self.gpu_scene.is_none() {
-            self.gpu_scene = Some(self.upload_to_gpu(device, queue, size));
+        if self.mesh_objects_dirty && !self.resources_dirty {
+            self.update_mesh_objects();
+            self.mesh_objects_dirty = false;
+        }
+
+        // Inserted or removed meshes, materials and textures change the layout of every buffer
+        if self.gpu_scene.is_none() || self.resources_dirty {
+            self.gpu_scene = Some(self.upload_to_gpu(device, queue));
+            self.gpu_resources_revision += 1;
+            self.resources_dirty = false;
+            self.materials_dirty = false;
+            self.lights_dirty = false;
+            self.environment_dirty = false;
+            self.mesh_objects_dirty = false;
+        }
+
+        if self.environment_dirty {
+            let (environment_view, environment_distribution_buffer) =
+                self.upload_environment(device, queue);
+
+            if let Some(gpu_scene) = &mut self.gpu_scene {
+                gpu_scene.environment_view = environment_view;
+                gpu_scene.environment_distribution_buffer = environment_distribution_buffer;
+            }
+            self.gpu_resources_revision += 1;
+            self.environment_dirty = false;
+        }
+
+        // Light and emission changes alter the light BVH, so its buffers are recreated
+        if self.materials_dirty || self.lights_dirty {
+            let (materials, material_map) = self.gpu_materials();
+            let lights = self.upload_lights(device, &material_map);
+
+            if let Some(gpu_scene) = &mut self.gpu_scene {
+                queue.write_buffer(
+                    &gpu_scene.material_buffer,
+                    0,
+                    bytemuck::cast_slice(&materials),
+                );
+                gpu_scene.lights = lights;
+            }
+            self.gpu_resources_revision += 1;
+            self.materials_dirty = false;
+            self.lights_dirty = false;
         }
 
         // `self.gpu_scene` should always be `Some()` at this point
         self.gpu_scene.as_ref().unwrap()
     }
 
-    fn upload_to_gpu(
+    /// Returns the resources uploaded by the last `get_or_upload_gpu_scene()` call
+    pub fn gpu_scene(&self) -> Option<&GpuScene> {
+        self.gpu_scene.as_ref()
+    }
+
+    /// Returns a counter that changes every time GPU resources are recreated
+    ///
+    /// Bind groups that reference resources of an older revision have to be recreated
+    pub fn gpu_resources_revision(&self) -> u64 {
+        self.gpu_resources_revision
+    }
+
+    fn gpu_materials(&self) -> (Vec<GpuMaterial>, FastHashMap<DenseStorageIndex, usize>) {
+        let texture_map = self.texture_map();
+        // Handles of removed textures are treated as if the material had no texture
+        let texture_index = |texture: Option<DenseStorageIndex>| {
+            texture
+                .and_then(|texture| texture_map.get(&texture))
+                .map_or(NO_TEXTURE, |&i| i as u32)
+        };
+
+        let mut materials = Vec::new();
+        let mut material_map = FastHashMap::default();
+
+        for (i, (generation, material)) in self.materials.iter().enumerate() {
+            let Some(material) = material else {
+                continue;
+            };
+
+            materials.push(GpuMaterial {
+                albedo_texture: texture_index(material.albedo_texture),
+                emissive_texture: texture_index(material.emissive_texture),
+                metallic_roughness_texture: texture_index(material.metallic_roughness_texture),
+                normal_texture: texture_index(material.normal_texture),
+                ..GpuMaterial::from(material)
+            });
+            material_map.insert(DenseStorageIndex(i, *generation), materials.len() - 1);
+        }
+
+        (materials, material_map)
+    }
+
+    /// Maps texture handles to their index in the texture array of the GPU scene
+    fn texture_map(&self) -> FastHashMap<DenseStorageIndex, usize> {
+        self.textures
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, texture))| texture.is_some())
+            .enumerate()
+            .map(|(array_index, (i, (generation, _)))| {
+                (DenseStorageIndex(i, *generation), array_index)
+            })
+            .collect()
+    }
+
+    fn upload_textures(
         &self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        size: PhysicalSize<u32>,
-    ) -> GpuScene {
-        let view = Mat4::from(self.camera.transform);
-        let proj = Mat4::perspective_rh(
-            self.camera.fov.to_radians(),
-            size.width as f32 / size.height as f32,
-            self.camera.near_clip,
-            self.camera.far_clip,
-        );
+    ) -> Vec<wgpu::TextureView> {
+        let placeholder = Texture {
+            width: 1,
+            height: 1,
+            pixels: vec![255; 4],
+            color_space: ColorSpace::Linear,
+        };
+
+        let mut textures: Vec<_> = self
+            .textures
+            .iter()
+            .filter_map(|(_, texture)| texture.as_ref())
+            .collect();
+
+        // Binding arrays can't be empty, so scenes without textures get a white placeholder
+        if textures.is_empty() {
+            textures.push(&placeholder);
+        }
+
+        textures
+            .into_iter()
+            .map(|texture| {
+                let gpu_texture = device.create_texture_with_data(
+                    queue,
+                    &wgpu::TextureDescriptor {
+                        label: Some("Material texture"),
+                        size: wgpu::Extent3d {
+                            width: texture.width,
+                            height: texture.height,
+                            depth_or_array_layers: 1,
+                        },
+                        mip_level_count: 1,
+                        sample_count: 1,
+                        dimension: wgpu::TextureDimension::D2,
+                        format: texture.format(),
+                        usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                        view_formats: &[],
+                    },
+                    wgpu::util::TextureDataOrder::LayerMajor,
+                    &texture.pixels,
+                );
+
+                gpu_texture.create_view(&wgpu::TextureViewDescriptor::default())
+            })
+            .collect()
+    }
 
-        let gpu_uniform = GpuUniform {
-            view_inverse: view.inverse(),
-            proj_inverse: proj.inverse(),
+    /// Applies inserted, removed and moved mesh objects to the uploaded scene, or marks it for a
+    /// full upload if the BLASes have to change
+    fn update_mesh_objects(&mut self) {
+        let groups = self.instance_groups();
+        let Some(gpu_scene) = &self.gpu_scene else {
+            return;
         };
-        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Uniform Buffer"),
-            contents: bytemuck::cast_slice(&[gpu_uniform]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
 
-        let mut mesh_objects = FastHashMap::<_, Vec<Transform>>::default();
+        match self.instance_change(
+            &groups,
+            &gpu_scene.instance_groups,
+            &gpu_scene.instance_transforms,
+        ) {
+            InstanceChange::Transforms { lights } => {
+                self.lights_dirty |= lights;
+
+                // The TLAS instances are rewritten every frame
+                if let Some(gpu_scene) = &mut self.gpu_scene {
+                    gpu_scene.instance_transforms = groups
+                        .into_iter()
+                        .map(|(_, _, transforms)| transforms)
+                        .collect();
+                }
+            }
+            InstanceChange::Groups => self.resources_dirty = true,
+        }
+    }
+
+    /// Compares the instance groups of the mesh objects with the uploaded ones
+    fn instance_change(
+        &self,
+        groups: &[(DenseStorageIndex, DenseStorageIndex, Vec<Transform>)],
+        uploaded_groups: &[(DenseStorageIndex, DenseStorageIndex)],
+        uploaded_transforms: &[Vec<Transform>],
+    ) -> InstanceChange {
+        // Mesh objects can be added, removed and moved without new BLASes as long as every
+        // mesh and material combination stays in the same place
+        let same_groups = groups.len() == uploaded_groups.len()
+            && groups
+                .iter()
+                .zip(uploaded_groups)
+                .all(|(group, uploaded)| (group.0, group.1) == *uploaded);
+        if !same_groups {
+            return InstanceChange::Groups;
+        }
+
+        // The instance emitter buffer has an entry per TLAS instance, and emissive triangles are
+        // stored in world space so only moving emissive objects changes them
+        let lights = groups.iter().zip(uploaded_transforms).any(
+            |((_, material, transforms), uploaded_transforms)| {
+                transforms.len() != uploaded_transforms.len()
+                    || (transforms != uploaded_transforms
+                        && self.materials.get(*material).is_some_and(is_emissive))
+            },
+        );
+
+        InstanceChange::Transforms { lights }
+    }
+
+    /// Groups mesh objects by mesh and material, every group becomes a BLAS with one TLAS instance
+    /// per transform
+    ///
+    /// Mesh objects whose mesh or material was removed are skipped.
+    fn instance_groups(&self) -> Vec<(DenseStorageIndex, DenseStorageIndex, Vec<Transform>)> {
+        let mut groups = Vec::new();
+        let mut group_map = FastHashMap::default();
 
         for mesh_object in self
             .mesh_objects
             .iter()
             .filter_map(|(_, mesh_object)| mesh_object.as_ref())
         {
-            mesh_objects
+            if self.meshes.get(mesh_object.mesh).is_none()
+                || self.materials.get(mesh_object.material).is_none()
+            {
+                continue;
+            }
+
+            let group = *group_map
                 .entry((mesh_object.mesh, mesh_object.material))
-                .or_default()
-                .push(mesh_object.transform);
+                .or_insert_with(|| {
+                    groups.push((mesh_object.mesh, mesh_object.material, Vec::new()));
+                    groups.len() - 1
+                });
+            groups[group].2.push(mesh_object.transform);
+        }
+
+        groups
+    }
+
+    /// Uploads the lights, the emissive triangles and the light BVH over both
+    ///
+    /// Emissive triangles are listed per TLAS instance in the order of `instance_groups()`, so the
+    /// shader can find the triangle a ray hit through the instance emitter buffer.
+    fn upload_lights(
+        &self,
+        device: &wgpu::Device,
+        material_map: &FastHashMap<DenseStorageIndex, usize>,
+    ) -> GpuLights {
+        let sun = self.sky.and_then(|sky| sky.sun_light());
+        let (directional_lights, bounded_lights): (Vec<_>, Vec<_>) = sun
+            .iter()
+            .chain(self.lights.iter().filter_map(|(_, light)| light.as_ref()))
+            .partition(|light| matches!(light.shape, LightShape::Directional { .. }));
+        let directional_light_count = directional_lights.len() as u32;
+
+        // Directional lights are infinitely far away and sampled separately from the BVH
+        let mut gpu_lights: Vec<_> = directional_lights.into_iter().map(GpuLight::from).collect();
+        let mut emitters = Vec::new();
+        for light in bounded_lights {
+            if let Some(bounds) = LightBounds::from_light(light) {
+                emitters.push((bounds, gpu_lights.len() as u32, false));
+            }
+            gpu_lights.push(GpuLight::from(light));
+        }
+
+        let mut triangles = Vec::new();
+        let mut instance_emitters = Vec::new();
+        for (mesh, material, transforms) in self.instance_groups() {
+            let (Some(mesh), Some(material), Some(&material_index)) = (
+                self.meshes.get(mesh),
+                self.materials.get(material),
+                material_map.get(&material),
+            ) else {
+                continue;
+            };
+
+            let luminance = luminance(material.emissive * material.emissive_strength);
+            for transform in transforms {
+                if !is_emissive(material) {
+                    instance_emitters.push(NO_EMITTER);
+                    continue;
+                }
+                instance_emitters.push(triangles.len() as u32);
+
+                // Every triangle is listed so the primitive index of a hit can be used directly
+                let matrix = Mat4::from(transform);
+                for triangle in mesh.indices.chunks_exact(3) {
+                    let [v0, v1, v2] = [0, 1, 2].map(|i| mesh.vertices[triangle[i] as usize]);
+                    let [p0, p1, p2] = [v0, v1, v2].map(|v| matrix.transform_point3(v.pos));
+                    let area = (p1 - p0).cross(p2 - p0).length() * 0.5;
+
+                    if let Some(bounds) = LightBounds::from_triangle([p0, p1, p2], luminance) {
+                        emitters.push((bounds, triangles.len() as u32, true));
+                    }
+                    triangles.push(GpuEmissiveTriangle {
+                        p0,
+                        material_index: material_index as u32,
+                        p1,
+                        area,
+                        p2,
+                        bvh_trail: 0,
+                        uv0: v0.uv,
+                        uv1: v1.uv,
+                        uv2: v2.uv,
+                        _p0: [0; 2],
+                    });
+                }
+            }
+        }
+
+        let mut nodes = Vec::new();
+        if !emitters.is_empty() {
+            build_light_bvh(&mut emitters, 0, 0, &mut nodes, &mut triangles);
         }
+        let light_bvh_node_count = nodes.len() as u32;
 
+        // Storage buffers can't be empty, the counts keep placeholders from being used
+        if gpu_lights.is_empty() {
+            gpu_lights.push(GpuLight::default());
+        }
+        if triangles.is_empty() {
+            triangles.push(GpuEmissiveTriangle::default());
+        }
+        if instance_emitters.is_empty() {
+            instance_emitters.push(NO_EMITTER);
+        }
+        if nodes.is_empty() {
+            nodes.push(GpuLightBvhNode::default());
+        }
+
+        let storage_buffer = |label, contents| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents,
+                usage: wgpu::BufferUsages::STORAGE,
+            })
+        };
+
+        GpuLights {
+            light_buffer: storage_buffer("Lights", bytemuck::cast_slice(&gpu_lights)),
+            emissive_triangle_buffer: storage_buffer(
+                "Emissive triangles",
+                bytemuck::cast_slice(&triangles),
+            ),
+            instance_emitter_buffer: storage_buffer(
+                "Instance emitters",
+                bytemuck::cast_slice(&instance_emitters),
+            ),
+            light_bvh_buffer: storage_buffer("Light BVH", bytemuck::cast_slice(&nodes)),
+            directional_light_count,
+            light_bvh_node_count,
+        }
+    }
+
+    fn upload_environment(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> (wgpu::TextureView, wgpu::Buffer) {
+        // Without an environment the shader uses the sky color, the placeholder only fills the
+        // bindings
+        let placeholder = Environment {
+            width: 1,
+            height: 1,
+            pixels: vec![Vec4::ZERO],
+            rotation: 0.0,
+            intensity: 0.0,
+        };
+        let environment = self.environment.as_ref().unwrap_or(&placeholder);
+
+        let texture = device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some("Environment"),
+                size: wgpu::Extent3d {
+                    width: environment.width,
+                    height: environment.height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba32Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            wgpu::util::TextureDataOrder::LayerMajor,
+            bytemuck::cast_slice(&environment.pixels),
+        );
+
+        let distribution_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment distribution"),
+            contents: bytemuck::cast_slice(&environment.sampling_distribution()),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        (
+            texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            distribution_buffer,
+        )
+    }
+
+    fn upload_to_gpu(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> GpuScene {
         let mut vertices = Vec::new();
         let mut indices = Vec::new();
         let mut mesh_map = FastHashMap::default();
@@ -152,28 +800,20 @@ impl Scene {
             );
         }
 
-        let mut materials = Vec::new();
-        let mut material_map = FastHashMap::default();
-
-        for (i, (generation, material)) in self.materials.iter().enumerate() {
-            let Some(material) = material else {
-                continue;
-            };
-
-            materials.push(GpuMaterial::from(material));
-            material_map.insert(DenseStorageIndex(i, *generation), materials.len() - 1);
-        }
+        let (materials, material_map) = self.gpu_materials();
 
         let mut instances = Vec::new();
+        let mut instance_groups = Vec::new();
         let mut instance_transforms = Vec::new();
 
-        for ((mesh, material), transforms) in mesh_objects {
+        for (mesh, material, transforms) in self.instance_groups() {
             let (Some((vertex_range, index_range)), Some(material_index)) =
                 (mesh_map.get(&mesh), material_map.get(&material))
             else {
                 continue;
             };
             instances.push((vertex_range, index_range, material_index));
+            instance_groups.push((mesh, material));
             instance_transforms.push(transforms);
         }
 
@@ -194,7 +834,7 @@ impl Scene {
         let material_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Materials"),
             contents: bytemuck::cast_slice(&materials),
-            usage: wgpu::BufferUsages::STORAGE,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
         });
         let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Instances"),
@@ -261,6 +901,10 @@ impl Scene {
             })
             .collect();
 
+        let (environment_view, environment_distribution_buffer) =
+            self.upload_environment(device, queue);
+        let lights = self.upload_lights(device, &material_map);
+
         let mut encoder =
             device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
 
@@ -269,11 +913,15 @@ impl Scene {
         queue.submit(Some(encoder.finish()));
 
         GpuScene {
-            uniform_buffer,
             vertex_buffer,
             index_buffer,
             material_buffer,
             instance_buffer,
+            texture_views: self.upload_textures(device, queue),
+            environment_view,
+            environment_distribution_buffer,
+            lights,
+            instance_groups,
             instance_transforms,
             bottom_level_acceleration_structures,
         }
@@ -282,11 +930,670 @@ impl Scene {
 
 #[derive(Debug, Clone)]
 pub struct GpuScene {
-    pub uniform_buffer: wgpu::Buffer,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub material_buffer: wgpu::Buffer,
     pub instance_buffer: wgpu::Buffer,
+    /// One view per texture, indexed by the texture indices of the materials
+    pub texture_views: Vec<wgpu::TextureView>,
+    pub environment_view: wgpu::TextureView,
+    /// The marginal and conditional CDFs from `Environment::sampling_distribution()`
+    pub environment_distribution_buffer: wgpu::Buffer,
+    pub lights: GpuLights,
+    /// The mesh and material of every BLAS
+    pub instance_groups: Vec<(DenseStorageIndex, DenseStorageIndex)>,
+    /// The transforms of the TLAS instances of every BLAS
     pub instance_transforms: Vec<Vec<Transform>>,
     pub bottom_level_acceleration_structures: Vec<wgpu::Blas>,
 }
+
+/// Explicit lights and emissive triangles, with a light BVH to pick between them
+#[derive(Debug, Clone)]
+pub struct GpuLights {
+    /// Directional lights first, followed by the lights referenced by the light BVH
+    pub light_buffer: wgpu::Buffer,
+    pub emissive_triangle_buffer: wgpu::Buffer,
+    /// The first emissive triangle of every TLAS instance or `NO_EMITTER`
+    pub instance_emitter_buffer: wgpu::Buffer,
+    pub light_bvh_buffer: wgpu::Buffer,
+    pub directional_light_count: u32,
+    pub light_bvh_node_count: u32,
+}
+
+/// How the uploaded scene has to change after mesh objects were edited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InstanceChange {
+    /// Only the TLAS instances changed, `lights` is set if the emissive triangles or the instance
+    /// emitters have to be uploaded again
+    Transforms { lights: bool },
+    /// A mesh and material combination was added or removed, which needs new BLASes
+    Groups,
+}
+
+fn luminance(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+/// Returns whether the material's triangles are added to the light BVH
+fn is_emissive(material: &Material) -> bool {
+    material.emissive_strength > 0.0
+        && luminance(material.emissive * material.emissive_strength) > 0.0
+}
+
+/// The spatial and directional bounds of emitters with their power, as in pbrt-v4's light BVH
+#[derive(Debug, Clone, Copy)]
+struct LightBounds {
+    min: Vec3,
+    max: Vec3,
+    phi: f32,
+    /// The axis of the cone that contains all surface normals
+    direction: Vec3,
+    cos_theta_o: f32,
+    /// Light is emitted up to this angle past the normal cone
+    cos_theta_e: f32,
+    two_sided: bool,
+}
+
+impl LightBounds {
+    /// Returns `None` for lights that can't be bounded or don't emit anything
+    fn from_light(light: &Light) -> Option<LightBounds> {
+        let position = light.transform.translation;
+        let rotation = light.transform.rotation;
+        let direction = rotation * Vec3::NEG_Z;
+        let emission = luminance(light.color * light.intensity);
+
+        let bounds = match light.shape {
+            LightShape::Point => LightBounds {
+                min: position,
+                max: position,
+                phi: 4.0 * std::f32::consts::PI * emission,
+                direction: Vec3::Z,
+                cos_theta_o: -1.0,
+                cos_theta_e: 0.0,
+                two_sided: false,
+            },
+            LightShape::Spot {
+                inner_angle,
+                outer_angle,
+            } => {
+                let inner_angle = inner_angle.min(outer_angle);
+
+                LightBounds {
+                    min: position,
+                    max: position,
+                    phi: 4.0 * std::f32::consts::PI * emission,
+                    direction,
+                    cos_theta_o: inner_angle.cos(),
+                    cos_theta_e: (outer_angle - inner_angle).cos(),
+                    two_sided: false,
+                }
+            }
+            LightShape::Directional { .. } => return None,
+            LightShape::Rect { width, height } => {
+                let half_extent = (rotation * Vec3::X * width * 0.5).abs()
+                    + (rotation * Vec3::Y * height * 0.5).abs();
+
+                LightBounds {
+                    min: position - half_extent,
+                    max: position + half_extent,
+                    phi: emission * width * height * std::f32::consts::PI,
+                    direction,
+                    cos_theta_o: 1.0,
+                    cos_theta_e: 0.0,
+                    two_sided: false,
+                }
+            }
+            LightShape::Disk { radius } => {
+                let half_extent =
+                    (rotation * Vec3::X * radius).abs() + (rotation * Vec3::Y * radius).abs();
+
+                LightBounds {
+                    min: position - half_extent,
+                    max: position + half_extent,
+                    phi: emission * std::f32::consts::PI * radius * radius * std::f32::consts::PI,
+                    direction,
+                    cos_theta_o: 1.0,
+                    cos_theta_e: 0.0,
+                    two_sided: false,
+                }
+            }
+        };
+
+        (bounds.phi > 0.0).then_some(bounds)
+    }
+
+    /// Bounds a two-sided emissive triangle with the luminance of its emitted radiance
+    fn from_triangle([p0, p1, p2]: [Vec3; 3], luminance: f32) -> Option<LightBounds> {
+        let area_normal = (p1 - p0).cross(p2 - p0);
+        let area = area_normal.length() * 0.5;
+
+        let bounds = LightBounds {
+            min: p0.min(p1).min(p2),
+            max: p0.max(p1).max(p2),
+            phi: luminance * area * 2.0 * std::f32::consts::PI,
+            direction: area_normal.normalize_or_zero(),
+            cos_theta_o: 1.0,
+            cos_theta_e: 0.0,
+            two_sided: true,
+        };
+
+        (bounds.phi > 0.0).then_some(bounds)
+    }
+
+    fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    fn union(&self, other: &LightBounds) -> LightBounds {
+        let (direction, cos_theta_o) = union_cones(
+            (self.direction, self.cos_theta_o),
+            (other.direction, other.cos_theta_o),
+        );
+
+        LightBounds {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            phi: self.phi + other.phi,
+            direction,
+            cos_theta_o,
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+            two_sided: self.two_sided || other.two_sided,
+        }
+    }
+
+    fn to_node(self, index: u32, flags: u32) -> GpuLightBvhNode {
+        GpuLightBvhNode {
+            bounds_min: self.min,
+            phi: self.phi,
+            bounds_max: self.max,
+            cos_theta_o: self.cos_theta_o,
+            direction: self.direction,
+            cos_theta_e: self.cos_theta_e,
+            index,
+            flags: flags
+                | if self.two_sided {
+                    LIGHT_BVH_TWO_SIDED
+                } else {
+                    0
+                },
+            _p0: [0; 2],
+        }
+    }
+}
+
+/// Returns the smallest cone (axis, cosine of the half angle) that contains both cones
+fn union_cones(a: (Vec3, f32), b: (Vec3, f32)) -> (Vec3, f32) {
+    let entire_sphere = (Vec3::Z, -1.0);
+
+    let theta_a = a.1.clamp(-1.0, 1.0).acos();
+    let theta_b = b.1.clamp(-1.0, 1.0).acos();
+    let theta_d = a.0.angle_between(b.0);
+
+    if (theta_d + theta_b).min(std::f32::consts::PI) <= theta_a {
+        return a;
+    }
+    if (theta_d + theta_a).min(std::f32::consts::PI) <= theta_b {
+        return b;
+    }
+
+    let theta_o = (theta_a + theta_d + theta_b) * 0.5;
+    if theta_o >= std::f32::consts::PI {
+        return entire_sphere;
+    }
+
+    // Rotate the axis of `a` toward `b` until the cone touches the far side of both
+    let rotation_axis = a.0.cross(b.0);
+    if rotation_axis.length_squared() == 0.0 {
+        return entire_sphere;
+    }
+    let rotation = Quat::from_axis_angle(rotation_axis.normalize(), theta_o - theta_a);
+
+    (rotation * a.0, theta_o.cos())
+}
+
+/// Recursively builds the light BVH in depth first order and returns the bounds of its root
+///
+/// Emitters are `(bounds, light or triangle index, is triangle)`. They're split at the median of
+/// the longest centroid axis, which keeps the tree balanced so `trail` never needs more than 32
+/// bits.
+fn build_light_bvh(
+    emitters: &mut [(LightBounds, u32, bool)],
+    trail: u32,
+    depth: u32,
+    nodes: &mut Vec<GpuLightBvhNode>,
+    triangles: &mut [GpuEmissiveTriangle],
+) -> LightBounds {
+    if let [(bounds, index, is_triangle)] = *emitters {
+        let mut flags = LIGHT_BVH_LEAF;
+        if is_triangle {
+            triangles[index as usize].bvh_trail = trail;
+            flags |= LIGHT_BVH_TRIANGLE;
+        }
+        nodes.push(bounds.to_node(index, flags));
+
+        return bounds;
+    }
+
+    let (centroid_min, centroid_max) = emitters.iter().fold(
+        (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+        |(min, max), (bounds, _, _)| (min.min(bounds.centroid()), max.max(bounds.centroid())),
+    );
+    let axis = (centroid_max - centroid_min).max_position();
+
+    let middle = emitters.len() / 2;
+    emitters.select_nth_unstable_by(middle, |(a, _, _), (b, _, _)| {
+        a.centroid()[axis].total_cmp(&b.centroid()[axis])
+    });
+
+    let node_index = nodes.len();
+    nodes.push(GpuLightBvhNode::default());
+
+    let (first, second) = emitters.split_at_mut(middle);
+    let first_bounds = build_light_bvh(first, trail, depth + 1, nodes, triangles);
+    let second_index = nodes.len() as u32;
+    let second_bounds = build_light_bvh(second, trail | 1 << depth, depth + 1, nodes, triangles);
+
+    let bounds = first_bounds.union(&second_bounds);
+    nodes[node_index] = bounds.to_node(second_index, 0);
+
+    bounds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count<T>(storage: &DenseStorage<T>) -> usize {
+        storage.iter().filter(|(_, value)| value.is_some()).count()
+    }
+
+    #[test]
+    fn load_gltf_fixture() {
+        let mut scene = Scene::default();
+        let mesh_objects = scene.load_gltf("assets/tests/hierarchy.gltf").unwrap();
+
+        // Two primitives, plus a sheared copy of both for the rotated child of the stretched node
+        assert_eq!(count(&scene.meshes), 4);
+        assert_eq!(count(&scene.materials), 2);
+        // The image is used as color and as a normal map
+        assert_eq!(count(&scene.textures), 2);
+        assert_eq!(mesh_objects.len(), 4);
+
+        let camera = scene.camera();
+        assert_eq!(camera.projection, crate::camera::Projection::Perspective);
+        assert!((camera.fov - 0.5f32.to_degrees()).abs() < 1e-4);
+        assert_eq!(camera.near_clip, 0.05);
+        assert_eq!(camera.transform.translation, Vec3::new(0.0, 0.0, 5.0));
+
+        let textured = scene.materials.iter().find_map(|(_, material)| {
+            material
+                .as_ref()
+                .filter(|material| material.albedo_texture.is_some())
+        });
+        let textured = textured.expect("the first material has a base color texture");
+        assert_eq!(textured.albedo_texture, textured.emissive_texture);
+        assert_eq!(textured.roughness, 0.5);
+    }
+
+    #[test]
+    fn load_gltf_keeps_shear() {
+        let mut scene = Scene::default();
+        let mesh_objects = scene.load_gltf("assets/tests/hierarchy.gltf").unwrap();
+
+        // The first mesh object belongs to the rotated child of the node scaled by (2, 1, 1)
+        let expected_matrix = Mat4::from_scale(Vec3::new(2.0, 1.0, 1.0))
+            * Mat4::from_quat(Quat::from_rotation_z(std::f32::consts::FRAC_PI_4));
+        let mesh_object = scene.mesh_objects.get(mesh_objects[0]).unwrap();
+        let mesh = scene.meshes.get(mesh_object.mesh).unwrap();
+        let original = [Vec3::ZERO, Vec3::X, Vec3::Y];
+
+        for (vertex, original) in mesh.vertices.iter().zip(original) {
+            let actual = Mat4::from(mesh_object.transform).transform_point3(vertex.pos);
+            let expected = expected_matrix.transform_point3(original);
+            assert!(actual.abs_diff_eq(expected, 1e-5), "{actual} != {expected}");
+        }
+    }
+
+    #[test]
+    fn load_obj_without_mtl_keeps_geometry() {
+        let mut scene = Scene::default();
+        let mesh_objects = scene.load_obj("assets/tests/missing_mtl.obj").unwrap();
+
+        assert_eq!(mesh_objects.len(), 2);
+        assert_eq!(count(&scene.meshes), 2);
+        // Both use the white default material
+        assert_eq!(count(&scene.materials), 1);
+        let material = scene
+            .materials
+            .iter()
+            .find_map(|(_, m)| m.as_ref())
+            .unwrap();
+        assert_eq!(material.albedo, Vec3::ONE);
+    }
+
+    #[test]
+    fn load_obj_materials() {
+        let mut scene = Scene::default();
+        let mesh_objects = scene.load_obj("assets/tests/materials.obj").unwrap();
+        assert_eq!(mesh_objects.len(), 3);
+        assert_eq!(count(&scene.textures), 0);
+
+        let [leaf, glass, plastic] = [0, 1, 2].map(|i| {
+            let mesh_object = scene.mesh_objects.get(mesh_objects[i]).unwrap();
+            *scene.materials.get(mesh_object.material).unwrap()
+        });
+
+        // Dissolve makes the leaf a cut-out instead of glass and the missing texture is skipped
+        assert_eq!(leaf.opacity, 0.5);
+        assert_eq!(leaf.transmission, 0.0);
+        assert_eq!(leaf.albedo_texture, None);
+        assert_eq!(leaf.albedo, Vec3::new(0.2, 0.6, 0.1));
+
+        assert_eq!(glass.transmission, 1.0);
+        assert_eq!(glass.ior, 1.33);
+        assert_eq!(glass.opacity, 1.0);
+        assert_eq!(glass.transmission_tint, Vec3::new(0.9, 0.8, 0.7));
+
+        assert_eq!(plastic.transmission, 0.0);
+        assert_eq!(plastic.ior, 1.45);
+        assert_eq!(plastic.opacity, 1.0);
+    }
+
+    /// Emissive triangles scattered on a grid with varying orientations and brightness, plus a
+    /// spot light
+    fn light_bvh_emitters() -> (Vec<(LightBounds, u32, bool)>, Vec<GpuEmissiveTriangle>) {
+        let mut emitters = Vec::new();
+        let mut triangles = Vec::new();
+
+        for i in 0..37 {
+            let offset = Vec3::new((i % 5) as f32, (i / 5 % 3) as f32, (i / 15) as f32 * 2.0);
+            let rotation = Quat::from_euler(glam::EulerRot::XYZ, i as f32, i as f32 * 0.7, 0.0);
+            let points = [Vec3::ZERO, Vec3::X, Vec3::Y].map(|p| offset + rotation * p * 0.3);
+            let bounds = LightBounds::from_triangle(points, 1.0 + i as f32).unwrap();
+
+            emitters.push((bounds, triangles.len() as u32, true));
+            triangles.push(GpuEmissiveTriangle::default());
+        }
+
+        let spot = Light {
+            shape: LightShape::Spot {
+                inner_angle: 0.3,
+                outer_angle: 0.5,
+            },
+            ..Default::default()
+        };
+        emitters.push((LightBounds::from_light(&spot).unwrap(), 0, false));
+
+        (emitters, triangles)
+    }
+
+    /// Returns whether the cone of `outer` contains the cone of `inner`
+    fn cone_contains(outer: (Vec3, f32), inner: (Vec3, f32)) -> bool {
+        if outer.1 <= -1.0 {
+            return true;
+        }
+
+        let theta_outer = outer.1.clamp(-1.0, 1.0).acos();
+        let theta_inner = inner.1.clamp(-1.0, 1.0).acos();
+        outer.0.angle_between(inner.0) + theta_inner <= theta_outer + 1e-4
+    }
+
+    #[test]
+    fn light_bvh_bounds_contain_their_children() {
+        let (mut emitters, mut triangles) = light_bvh_emitters();
+        let mut nodes = Vec::new();
+        let root = build_light_bvh(&mut emitters, 0, 0, &mut nodes, &mut triangles);
+
+        assert_eq!(nodes[0].phi, root.phi);
+        let leaf_count = nodes
+            .iter()
+            .filter(|node| node.flags & LIGHT_BVH_LEAF != 0)
+            .count();
+        assert_eq!(leaf_count, emitters.len());
+
+        for (i, node) in nodes.iter().enumerate() {
+            if node.flags & LIGHT_BVH_LEAF != 0 {
+                continue;
+            }
+
+            let children = [&nodes[i + 1], &nodes[node.index as usize]];
+            for child in children {
+                assert!(child.bounds_min.cmpge(node.bounds_min).all());
+                assert!(child.bounds_max.cmple(node.bounds_max).all());
+                assert!(child.cos_theta_e >= node.cos_theta_e);
+                assert!(cone_contains(
+                    (node.direction, node.cos_theta_o),
+                    (child.direction, child.cos_theta_o),
+                ));
+            }
+
+            let phi = children[0].phi + children[1].phi;
+            assert!((node.phi - phi).abs() <= 1e-4 * phi);
+        }
+    }
+
+    #[test]
+    fn light_bvh_trails_reach_every_triangle() {
+        let (mut emitters, mut triangles) = light_bvh_emitters();
+        let mut nodes = Vec::new();
+        build_light_bvh(&mut emitters, 0, 0, &mut nodes, &mut triangles);
+
+        // Walks the tree like `light_bvh_pmf` in the shader
+        for (i, triangle) in triangles.iter().enumerate() {
+            let mut bits = triangle.bvh_trail;
+            let mut node_index = 0;
+            while nodes[node_index].flags & LIGHT_BVH_LEAF == 0 {
+                node_index = if bits & 1 != 0 {
+                    nodes[node_index].index as usize
+                } else {
+                    node_index + 1
+                };
+                bits >>= 1;
+            }
+
+            let leaf = &nodes[node_index];
+            assert_ne!(leaf.flags & LIGHT_BVH_TRIANGLE, 0);
+            assert_eq!(leaf.index as usize, i);
+        }
+    }
+
+    #[test]
+    fn union_cones_contains_both_cones() {
+        let cones = [
+            (Vec3::Z, 1.0),
+            (Vec3::X, 0.9),
+            (Vec3::new(1.0, 1.0, 0.0).normalize(), 0.5),
+            (Vec3::NEG_Z, 1.0),
+            (Vec3::Y, -1.0),
+        ];
+
+        for a in cones {
+            for b in cones {
+                let union = union_cones(a, b);
+                assert!(union.0.is_normalized());
+                assert!(cone_contains(union, a), "{a:?} {b:?} -> {union:?}");
+                assert!(cone_contains(union, b), "{a:?} {b:?} -> {union:?}");
+            }
+        }
+
+        // A cone inside the other is returned unchanged, opposite directions cover everything
+        assert_eq!(union_cones((Vec3::Z, 0.0), (Vec3::Z, 0.5)), (Vec3::Z, 0.0));
+        assert_eq!(union_cones((Vec3::Z, 1.0), (Vec3::NEG_Z, 1.0)).1, -1.0);
+    }
+
+    /// A plain and an emissive mesh object that share a mesh
+    fn moving_objects() -> (Scene, DenseStorageIndex, DenseStorageIndex) {
+        let mut scene = Scene::default();
+        let mesh = scene.insert_mesh(Mesh::default());
+        let plain = scene.insert_material(Material::default());
+        let emissive = scene.insert_material(Material {
+            emissive: Vec3::ONE,
+            emissive_strength: 1.0,
+            ..Default::default()
+        });
+        let plain = scene.insert_mesh_object(MeshObject {
+            mesh,
+            material: plain,
+            transform: Transform::default(),
+        });
+        let emissive = scene.insert_mesh_object(MeshObject {
+            mesh,
+            material: emissive,
+            transform: Transform::default(),
+        });
+
+        (scene, plain, emissive)
+    }
+
+    /// Compares the current instance groups with ones taken as if they had been uploaded
+    fn change_since(
+        scene: &Scene,
+        uploaded: Vec<(DenseStorageIndex, DenseStorageIndex, Vec<Transform>)>,
+    ) -> InstanceChange {
+        let (groups, transforms): (Vec<_>, Vec<_>) = uploaded
+            .into_iter()
+            .map(|(mesh, material, transforms)| ((mesh, material), transforms))
+            .unzip();
+
+        scene.instance_change(&scene.instance_groups(), &groups, &transforms)
+    }
+
+    fn moved(translation: Vec3) -> Transform {
+        Transform {
+            translation,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn moving_plain_objects_keeps_lights() {
+        let (mut scene, plain, _) = moving_objects();
+        let uploaded = scene.instance_groups();
+        assert!(scene.set_transform(plain, moved(Vec3::X)));
+
+        assert_eq!(
+            change_since(&scene, uploaded),
+            InstanceChange::Transforms { lights: false }
+        );
+    }
+
+    #[test]
+    fn moving_emissive_objects_updates_lights() {
+        let (mut scene, _, emissive) = moving_objects();
+        let uploaded = scene.instance_groups();
+        assert!(scene.set_transform(emissive, moved(Vec3::X)));
+
+        assert_eq!(
+            change_since(&scene, uploaded),
+            InstanceChange::Transforms { lights: true }
+        );
+    }
+
+    #[test]
+    fn new_instances_update_lights() {
+        let (mut scene, plain, _) = moving_objects();
+        let uploaded = scene.instance_groups();
+        let mesh_object = *scene.mesh_objects.get(plain).unwrap();
+        scene.insert_mesh_object(MeshObject {
+            transform: moved(Vec3::Y),
+            ..mesh_object
+        });
+
+        assert_eq!(
+            change_since(&scene, uploaded),
+            InstanceChange::Transforms { lights: true }
+        );
+    }
+
+    #[test]
+    fn new_materials_change_groups() {
+        let (mut scene, plain, _) = moving_objects();
+        let uploaded = scene.instance_groups();
+        let material = scene.insert_material(Material::default());
+        scene.get_mesh_object_mut(plain).unwrap().material = material;
+
+        assert_eq!(change_since(&scene, uploaded), InstanceChange::Groups);
+    }
+
+    #[test]
+    fn set_transform_marks_mesh_objects_dirty() {
+        let (mut scene, plain, _) = moving_objects();
+        scene.mesh_objects_dirty = false;
+        let revision = scene.revision();
+
+        assert!(scene.set_transform(plain, moved(Vec3::Z)));
+        assert!(scene.mesh_objects_dirty);
+        assert!(scene.revision() > revision);
+        assert_eq!(
+            scene.mesh_objects.get(plain).unwrap().transform,
+            moved(Vec3::Z)
+        );
+
+        // Stale handles don't touch the scene
+        scene.remove_mesh_object(plain);
+        scene.mesh_objects_dirty = false;
+        let revision = scene.revision();
+        assert!(!scene.set_transform(plain, Transform::default()));
+        assert!(!scene.mesh_objects_dirty);
+        assert_eq!(scene.revision(), revision);
+    }
+
+    #[test]
+    fn removed_meshes_and_materials_skip_their_objects() {
+        let (mut scene, plain, emissive) = moving_objects();
+        let plain = *scene.mesh_objects.get(plain).unwrap();
+        let emissive = *scene.mesh_objects.get(emissive).unwrap();
+        let other_mesh = scene.insert_mesh(Mesh::default());
+        scene.insert_mesh_object(MeshObject {
+            mesh: other_mesh,
+            ..plain
+        });
+
+        scene.resources_dirty = false;
+        assert!(scene.remove_material(plain.material).is_some());
+        assert!(scene.resources_dirty);
+        let groups = scene.instance_groups();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            (groups[0].0, groups[0].1),
+            (emissive.mesh, emissive.material)
+        );
+
+        scene.resources_dirty = false;
+        assert!(scene.remove_mesh(emissive.mesh).is_some());
+        assert!(scene.resources_dirty);
+        assert!(scene.instance_groups().is_empty());
+
+        // Stale handles don't mark anything dirty
+        scene.resources_dirty = false;
+        assert!(scene.remove_mesh(emissive.mesh).is_none());
+        assert!(!scene.resources_dirty);
+    }
+
+    #[test]
+    fn removed_textures_are_unbound() {
+        let mut scene = Scene::default();
+        let texture = scene.insert_texture(Texture {
+            width: 1,
+            height: 1,
+            pixels: vec![255; 4],
+            color_space: ColorSpace::Srgb,
+        });
+        let material = scene.insert_material(Material {
+            albedo_texture: Some(texture),
+            ..Default::default()
+        });
+
+        let (materials, material_map) = scene.gpu_materials();
+        assert_eq!(materials[material_map[&material]].albedo_texture, 0);
+
+        scene.resources_dirty = false;
+        assert!(scene.remove_texture(texture).is_some());
+        assert!(scene.resources_dirty);
+        let (materials, material_map) = scene.gpu_materials();
+        assert_eq!(
+            materials[material_map[&material]].albedo_texture,
+            NO_TEXTURE
+        );
+    }
+}
@@ -1,30 +1,140 @@
+use std::f32::consts::PI;
+
 use bytemuck::{Pod, Zeroable};
-use glam::{Mat4, Vec3};
+use glam::{Mat4, Vec2, Vec3, Vec4};
 
-use crate::{material::Material, mesh::Vertex};
+use crate::{
+    light::{Light, LightShape},
+    material::Material,
+    mesh::Vertex,
+    sky::Sky,
+    tone_mapping::ToneMapping,
+};
 
 #[repr(C)]
-#[derive(Clone, Copy, Pod, Zeroable)]
+#[derive(Clone, Copy, Pod, Zeroable, Default)]
 pub struct GpuUniform {
     pub view_inverse: Mat4,
     pub proj_inverse: Mat4,
+    pub sky_color: Vec3,
+    pub samples_per_pixel: u32,
+    pub frame_index: u32,
+    pub max_bounces: u32,
+    pub seed: u32,
+    pub firefly_clamp: f32,
+    pub min_distance: f32,
+    pub max_distance: f32,
+    pub environment_rotation: f32,
+    pub environment_intensity: f32,
+    /// Non-zero if misses sample the environment texture instead of `sky_color`
+    pub use_environment: u32,
+    /// The number of directional lights at the start of the light buffer
+    pub directional_light_count: u32,
+    pub light_bvh_node_count: u32,
+    /// Non-zero if misses see `sky` instead of `sky_color`, ignored if `use_environment` is set
+    pub use_sky: u32,
+    pub sky: GpuSky,
+    /// Zero for a pinhole camera
+    pub aperture_radius: f32,
+    /// Distance along the view direction to the plane in focus
+    pub focus_distance: f32,
+    /// The number of aperture blades, zero for a circular aperture
+    pub bokeh_blades: u32,
+    pub bokeh_rotation: f32,
+    pub projection: u32,
+    /// Field of view across the image height in radians for fisheye projections
+    pub fisheye_fov: f32,
+    /// The AOVs written to the AOV buffer, bit `Aov::index()` is set for every AOV
+    pub aov_mask: u32,
+    /// The index of the AOV shown in the output texture instead of the color, or `NO_AOV`
+    pub view_aov: u32,
+}
+
+/// The precomputed Preetham sky model
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Default)]
+pub struct GpuSky {
+    /// Perez coefficients A to E for x, y and Y, the last component is unused
+    pub perez: [Vec4; 5],
+    pub zenith_scale: Vec3,
+    pub _p0: u32,
+    /// Points toward the sun, kept above the horizon
+    pub sun_direction: Vec3,
+    /// Rays that miss the scene see the sun disk, weighted against shadow rays toward the sun
+    pub sun_cos_angular_radius: f32,
+    pub sun_radiance: Vec3,
+    pub _p1: u32,
+    pub ground_radiance: Vec3,
+    pub _p2: u32,
+}
+
+impl From<&Sky> for GpuSky {
+    fn from(value: &Sky) -> Self {
+        let cos_angular_radius = value.sun_angular_radius.cos();
+        // The radiance that gives the sun irradiance over the solid angle of its disk
+        let sun_radiance = match value.sun_light() {
+            Some(_) if cos_angular_radius < 1.0 => {
+                value.sun_irradiance() / (PI * (1.0 - cos_angular_radius * cos_angular_radius))
+            }
+            _ => Vec3::ZERO,
+        };
+
+        Self {
+            perez: value.perez_coefficients().map(|c| c.extend(0.0)),
+            zenith_scale: value.zenith_scale(),
+            _p0: 0,
+            sun_direction: value.sun_model_direction(),
+            sun_cos_angular_radius: cos_angular_radius,
+            sun_radiance,
+            _p1: 0,
+            ground_radiance: value.ground_radiance(),
+            _p2: 0,
+        }
+    }
+}
+
+/// The uniform of the blit pass
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Default)]
+pub struct GpuToneMapping {
+    /// The columns of the white balance matrix, the last components are padding
+    pub white_balance: [Vec4; 3],
+    /// The exposure as a factor instead of stops
+    pub exposure_scale: f32,
+    pub operator_index: u32,
+    pub _p0: [u32; 2],
+}
+
+impl From<&ToneMapping> for GpuToneMapping {
+    fn from(value: &ToneMapping) -> Self {
+        let white_balance = value.white_balance();
+
+        Self {
+            white_balance: [0, 1, 2].map(|i| white_balance.col(i).extend(0.0)),
+            exposure_scale: value.exposure.exp2(),
+            operator_index: value.operator.index(),
+            _p0: [0; 2],
+        }
+    }
 }
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable, Default)]
 pub struct GpuVertex {
     pub pos: Vec3,
-    _p0: u32,
+    // The texture coordinates are split up to fill the padding after each `Vec3`
+    pub u: f32,
     pub normal: Vec3,
-    _p1: u32,
+    pub v: f32,
 }
 
 impl From<Vertex> for GpuVertex {
     fn from(value: Vertex) -> Self {
         Self {
             pos: value.pos,
+            u: value.uv.x,
             normal: value.normal,
-            ..Default::default()
+            v: value.uv.y,
         }
     }
 }
@@ -33,8 +143,9 @@ impl From<&Vertex> for GpuVertex {
     fn from(value: &Vertex) -> Self {
         Self {
             pos: value.pos,
+            u: value.uv.x,
             normal: value.normal,
-            ..Default::default()
+            v: value.uv.y,
         }
     }
 }
@@ -48,13 +159,60 @@ pub struct GpuInstance {
     pub _p0: u32,
 }
 
+/// Marks an unused texture slot in `GpuMaterial`
+pub const NO_TEXTURE: u32 = u32::MAX;
+
+/// The uniforms of one denoiser pass, the passes of a frame use consecutive slots of
+/// `DENOISE_UNIFORM_STRIDE` bytes
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Default)]
+pub struct GpuDenoiseUniform {
+    pub previous_view_projection: Mat4,
+    pub aov_mask: u32,
+    pub mode: u32,
+    /// Non-zero if points can be reprojected with `previous_view_projection`
+    pub reproject: u32,
+    /// The distance between the taps of an à-trous iteration in pixels
+    pub step: u32,
+    pub color_phi: f32,
+    pub normal_phi: f32,
+    pub depth_phi: f32,
+    pub albedo_phi: f32,
+    pub temporal_alpha: f32,
+    pub accumulated_frames: u32,
+    /// Non-zero for the last iteration, which writes to the render target
+    pub write_target: u32,
+    pub _p0: u32,
+}
+
+/// Dynamic uniform buffer offsets have to be aligned to this
+pub const DENOISE_UNIFORM_STRIDE: u64 = 256;
+
+/// Shows the color instead of an AOV
+pub const NO_AOV: u32 = u32::MAX;
+
+pub const PROJECTION_PERSPECTIVE: u32 = 0;
+pub const PROJECTION_ORTHOGRAPHIC: u32 = 1;
+pub const PROJECTION_EQUIRECTANGULAR: u32 = 2;
+pub const PROJECTION_FISHEYE: u32 = 3;
+
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable, Default, Debug)]
 pub struct GpuMaterial {
     pub albedo: Vec3,
-    pub _p1: u32,
+    pub metallic: f32,
     pub emissive: Vec3,
     pub emissive_strength: f32,
+    pub transmission_tint: Vec3,
+    pub roughness: f32,
+    pub ior: f32,
+    pub transmission: f32,
+    pub albedo_texture: u32,
+    pub emissive_texture: u32,
+    pub metallic_roughness_texture: u32,
+    pub normal_texture: u32,
+    pub opacity: f32,
+    pub _p0: u32,
 }
 
 impl From<Material> for GpuMaterial {
@@ -63,6 +221,16 @@ impl From<Material> for GpuMaterial {
             albedo: value.albedo,
             emissive: value.emissive,
             emissive_strength: value.emissive_strength,
+            metallic: value.metallic,
+            roughness: value.roughness,
+            transmission_tint: value.transmission_tint,
+            ior: value.ior,
+            transmission: value.transmission,
+            opacity: value.opacity,
+            albedo_texture: NO_TEXTURE,
+            emissive_texture: NO_TEXTURE,
+            metallic_roughness_texture: NO_TEXTURE,
+            normal_texture: NO_TEXTURE,
             ..Default::default()
         }
     }
@@ -74,7 +242,126 @@ impl From<&Material> for GpuMaterial {
             albedo: value.albedo,
             emissive: value.emissive,
             emissive_strength: value.emissive_strength,
+            metallic: value.metallic,
+            roughness: value.roughness,
+            transmission_tint: value.transmission_tint,
+            ior: value.ior,
+            transmission: value.transmission,
+            opacity: value.opacity,
+            albedo_texture: NO_TEXTURE,
+            emissive_texture: NO_TEXTURE,
+            metallic_roughness_texture: NO_TEXTURE,
+            normal_texture: NO_TEXTURE,
             ..Default::default()
         }
     }
 }
+
+pub const LIGHT_POINT: u32 = 0;
+pub const LIGHT_SPOT: u32 = 1;
+pub const LIGHT_DIRECTIONAL: u32 = 2;
+pub const LIGHT_RECT: u32 = 3;
+pub const LIGHT_DISK: u32 = 4;
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Default, Debug)]
+pub struct GpuLight {
+    pub position: Vec3,
+    pub kind: u32,
+    /// The direction the light points in
+    pub direction: Vec3,
+    /// Cosine of the outer spot angle or of the directional angular radius
+    pub cos_outer: f32,
+    /// `color * intensity`
+    pub emission: Vec3,
+    pub cos_inner: f32,
+    /// Half the width (rect) or the radius (disk) along the local X axis
+    pub axis_u: Vec3,
+    pub _p0: u32,
+    /// Half the height (rect) or the radius (disk) along the local Y axis
+    pub axis_v: Vec3,
+    pub _p1: u32,
+}
+
+impl From<&Light> for GpuLight {
+    fn from(value: &Light) -> Self {
+        let rotation = value.transform.rotation;
+        let (kind, cos_outer, cos_inner, half_u, half_v) = match value.shape {
+            LightShape::Point => (LIGHT_POINT, -1.0, -1.0, 0.0, 0.0),
+            LightShape::Spot {
+                inner_angle,
+                outer_angle,
+            } => (
+                LIGHT_SPOT,
+                outer_angle.cos(),
+                inner_angle.min(outer_angle).cos(),
+                0.0,
+                0.0,
+            ),
+            LightShape::Directional { angular_radius } => {
+                (LIGHT_DIRECTIONAL, angular_radius.cos(), 1.0, 0.0, 0.0)
+            }
+            LightShape::Rect { width, height } => (LIGHT_RECT, 0.0, 0.0, width * 0.5, height * 0.5),
+            LightShape::Disk { radius } => (LIGHT_DISK, 0.0, 0.0, radius, radius),
+        };
+
+        Self {
+            position: value.transform.translation,
+            kind,
+            direction: rotation * Vec3::NEG_Z,
+            cos_outer,
+            emission: value.color * value.intensity,
+            cos_inner,
+            axis_u: rotation * Vec3::X * half_u,
+            _p0: 0,
+            axis_v: rotation * Vec3::Y * half_v,
+            _p1: 0,
+        }
+    }
+}
+
+/// A world space triangle of an emissive mesh object that can be sampled directly
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Default, Debug)]
+pub struct GpuEmissiveTriangle {
+    pub p0: Vec3,
+    pub material_index: u32,
+    pub p1: Vec3,
+    pub area: f32,
+    pub p2: Vec3,
+    /// The path from the root of the light BVH to this triangle, bit `i` is set if the second
+    /// child is taken at depth `i`
+    pub bvh_trail: u32,
+    pub uv0: Vec2,
+    pub uv1: Vec2,
+    pub uv2: Vec2,
+    pub _p0: [u32; 2],
+}
+
+/// Marks mesh object instances without emissive triangles in the instance emitter buffer
+pub const NO_EMITTER: u32 = u32::MAX;
+
+pub const LIGHT_BVH_LEAF: u32 = 1;
+/// Set on leaves that point at an emissive triangle instead of a light
+pub const LIGHT_BVH_TRIANGLE: u32 = 2;
+pub const LIGHT_BVH_TWO_SIDED: u32 = 4;
+
+/// A node of the light BVH with the bounds of all emitters below it
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Default, Debug)]
+pub struct GpuLightBvhNode {
+    pub bounds_min: Vec3,
+    /// The total emitted power
+    pub phi: f32,
+    pub bounds_max: Vec3,
+    /// Cosine of the cone around `direction` that contains all surface normals
+    pub cos_theta_o: f32,
+    pub direction: Vec3,
+    /// Cosine of the angle beyond the normals in which light is still emitted
+    pub cos_theta_e: f32,
+    /// The second child of interior nodes (the first one directly follows the node), or the light
+    /// or emissive triangle index of leaves
+    pub index: u32,
+    pub flags: u32,
+    pub _p0: [u32; 2],
+}
@@ -22,12 +22,125 @@ fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
     return result;
 }
 
+struct ToneMapping {
+    // Linear sRGB to linear sRGB
+    white_balance: mat3x3<f32>,
+    // 2 to the power of the exposure in stops
+    exposure_scale: f32,
+    operator_index: u32,
+};
+
+const OPERATOR_CLAMP: u32 = 0u;
+const OPERATOR_REINHARD: u32 = 1u;
+const OPERATOR_ACES_FILMIC: u32 = 2u;
+const OPERATOR_AGX: u32 = 3u;
+const OPERATOR_KHRONOS_PBR_NEUTRAL: u32 = 4u;
+
+// Linear radiance
 @group(0) @binding(0)
 var rt_color: texture_2d<f32>;
 @group(0) @binding(1)
 var rt_sampler: sampler;
+@group(0) @binding(2)
+var<uniform> tone_mapping: ToneMapping;
 
+// Outputs linear colors, the sRGB view of the surface encodes them
 @fragment
 fn fs_main(vertex: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(rt_color, rt_sampler, vertex.tex_coords);
+    let radiance = textureSample(rt_color, rt_sampler, vertex.tex_coords).rgb;
+    let color = max(tone_mapping.white_balance * radiance * tone_mapping.exposure_scale, vec3<f32>());
+
+    var mapped: vec3<f32>;
+    switch tone_mapping.operator_index {
+        case OPERATOR_REINHARD: {
+            mapped = color / (1.0 + color);
+        }
+        case OPERATOR_ACES_FILMIC: {
+            mapped = aces_filmic(color);
+        }
+        case OPERATOR_AGX: {
+            mapped = agx(color);
+        }
+        case OPERATOR_KHRONOS_PBR_NEUTRAL: {
+            mapped = khronos_pbr_neutral(color);
+        }
+        default: {
+            mapped = color;
+        }
+    }
+
+    return vec4<f32>(clamp(mapped, vec3<f32>(), vec3<f32>(1.0)), 1.0);
+}
+
+// Stephen Hill's fit of the ACES reference rendering and output transforms
+fn aces_filmic(color: vec3<f32>) -> vec3<f32> {
+    // The columns hold the rows of the matrices, so they're multiplied from the left
+    let input = mat3x3<f32>(
+        vec3<f32>(0.59719, 0.35458, 0.04823),
+        vec3<f32>(0.07600, 0.90834, 0.01566),
+        vec3<f32>(0.02840, 0.13383, 0.83777),
+    );
+    let output = mat3x3<f32>(
+        vec3<f32>(1.60475, -0.53108, -0.07367),
+        vec3<f32>(-0.10208, 1.10813, -0.00605),
+        vec3<f32>(-0.00327, -0.07276, 1.07602),
+    );
+
+    let v = color * input;
+    let a = v * (v + 0.0245786) - 9.0537e-05;
+    let b = v * (0.983729 * v + 0.432951) + 0.238081;
+
+    return (a / b) * output;
+}
+
+// Troy Sobotka's AgX with Benjamin Wrensch's polynomial fit of the default contrast
+fn agx(color: vec3<f32>) -> vec3<f32> {
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+
+    let inset = mat3x3<f32>(
+        vec3<f32>(0.84247905, 0.042328242, 0.042375654),
+        vec3<f32>(0.0784336, 0.87846863, 0.0784336),
+        vec3<f32>(0.079223745, 0.07916613, 0.879143),
+    );
+    let outset = mat3x3<f32>(
+        vec3<f32>(1.196879, -0.052896854, -0.052971635),
+        vec3<f32>(-0.09802088, 1.1519032, -0.09804345),
+        vec3<f32>(-0.09902974, -0.098961174, 1.1510737),
+    );
+
+    let log = log2(max(inset * color, vec3<f32>(1e-10)));
+    let x = (clamp(log, vec3<f32>(min_ev), vec3<f32>(max_ev)) - min_ev) / (max_ev - min_ev);
+
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    let curve = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+
+    // The curve produces display encoded values, the 2.2 power makes them linear again
+    return pow(max(outset * curve, vec3<f32>()), vec3<f32>(2.2));
+}
+
+// The Khronos PBR Neutral tone mapper, which keeps base colors intact below a threshold
+fn khronos_pbr_neutral(input: vec3<f32>) -> vec3<f32> {
+    let start_compression = 0.8 - 0.04;
+    let desaturation = 0.15;
+
+    let x = min(input.r, min(input.g, input.b));
+    var offset = 0.04;
+    if x < 0.08 {
+        offset = x - 6.25 * x * x;
+    }
+    var color = input - offset;
+
+    let peak = max(color.r, max(color.g, color.b));
+    if peak < start_compression {
+        return color;
+    }
+
+    let d = 1.0 - start_compression;
+    let new_peak = 1.0 - d * d / (peak + d - start_compression);
+    color *= new_peak / peak;
+
+    let g = 1.0 - 1.0 / (desaturation * (peak - new_peak) + 1.0);
+    return mix(color, vec3<f32>(new_peak), g);
 }
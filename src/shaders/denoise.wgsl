@@ -0,0 +1,274 @@
+struct DenoiseUniforms {
+    // The view projection matrix of the previous frame
+    previous_view_projection: mat4x4<f32>,
+    // The AOVs in `aovs`, bit `AOV_*` is set for every AOV
+    aov_mask: u32,
+    mode: u32,
+    // Non-zero if points can be reprojected with `previous_view_projection`, panoramas and
+    // fisheyes reuse the history of the same pixel
+    reproject: u32,
+    // The distance between the taps of this à-trous iteration in pixels
+    step: u32,
+    color_phi: f32,
+    normal_phi: f32,
+    depth_phi: f32,
+    albedo_phi: f32,
+    // The smallest weight of the current frame in the temporal accumulation
+    temporal_alpha: f32,
+    // The number of frames in the color since accumulation was last reset
+    accumulated_frames: u32,
+    // Non-zero for the last iteration, which writes to `output` instead of `filter_output`
+    write_target: u32,
+};
+
+// The temporally integrated color and the features of a pixel, kept for the next frame
+struct History {
+    color: vec3<f32>,
+    // The number of frames integrated into `color`, zero if the pixel has no history
+    length: f32,
+    position: vec3<f32>,
+    // The first two moments of the luminance
+    moment_1: f32,
+    normal: vec3<f32>,
+    moment_2: f32,
+};
+
+const MODE_SVGF: u32 = 2u;
+
+const AOV_ALBEDO: u32 = 0u;
+const AOV_NORMAL: u32 = 1u;
+const AOV_DEPTH: u32 = 2u;
+const AOV_POSITION: u32 = 3u;
+
+@group(0) @binding(0)
+var<uniform> uniforms: DenoiseUniforms;
+
+// The AOV accumulation buffer of `rt_compute.wgsl`
+@group(0) @binding(1)
+var<storage, read> aovs: array<vec4<f32>>;
+
+// The accumulated radiance
+@group(1) @binding(0)
+var color: texture_2d<f32>;
+
+@group(1) @binding(1)
+var<storage, read> previous_history: array<History>;
+
+@group(1) @binding(2)
+var<storage, read_write> history: array<History>;
+
+// The color to filter in `rgb` and the variance of its luminance in `a`
+@group(1) @binding(3)
+var<storage, read_write> integrated: array<vec4<f32>>;
+
+@group(1) @binding(4)
+var<storage, read> filter_input: array<vec4<f32>>;
+
+@group(1) @binding(5)
+var<storage, read_write> filter_output: array<vec4<f32>>;
+
+// The render target, which the blit pass shows
+@group(1) @binding(6)
+var output: texture_storage_2d<rgba16float, write>;
+
+// Prepares the color for filtering and estimates its variance. SVGF integrates the color with the
+// reprojected history of the last frames and uses the temporal variance of the luminance, plain
+// à-trous only estimates the variance from the neighbourhood.
+@compute @workgroup_size(8, 8)
+fn temporal(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let size = textureDimensions(color);
+    if any(global_id.xy >= size) {
+        return;
+    }
+
+    let pixel_count = size.x * size.y;
+    let pixel_index = global_id.x + global_id.y * size.x;
+    let current = textureLoad(color, global_id.xy, 0).rgb;
+
+    if uniforms.mode != MODE_SVGF {
+        integrated[pixel_index] = vec4<f32>(current, spatial_variance(global_id.xy, size));
+        return;
+    }
+
+    let position = load_aov(AOV_POSITION, pixel_index, pixel_count);
+    let normal = load_aov(AOV_NORMAL, pixel_index, pixel_count);
+    let depth = load_aov(AOV_DEPTH, pixel_index, pixel_count).x;
+
+    // The pixel that saw the same point in the previous frame
+    var previous_pixel = vec2<i32>(global_id.xy);
+    var in_front = true;
+    if uniforms.reproject != 0u {
+        let clip = uniforms.previous_view_projection * vec4<f32>(position, 1.0);
+        let ndc = clip.xy / clip.w;
+        previous_pixel = vec2<i32>(floor((vec2<f32>(ndc.x, -ndc.y) * 0.5 + 0.5) * vec2<f32>(size)));
+        in_front = clip.w > 0.0;
+    }
+
+    // Zero length if there is no usable history
+    var previous: History;
+    if depth > 0.0 && in_front && all(previous_pixel >= vec2<i32>()) && all(previous_pixel < vec2<i32>(size)) {
+        let candidate = previous_history[u32(previous_pixel.x) + u32(previous_pixel.y) * size.x];
+
+        // Disoccluded pixels saw a different surface in the previous frame
+        let same_surface = distance(candidate.position, position) < 0.05 * depth && dot(candidate.normal, normal) > 0.9;
+        if same_surface {
+            previous = candidate;
+        }
+    }
+
+    // The accumulated color already averages several frames. The history counts as at most as
+    // many frames as `temporal_alpha` allows, so a still camera converges to the accumulated color.
+    let history_frames = min(previous.length, 1.0 / uniforms.temporal_alpha - 1.0);
+    let frames = f32(uniforms.accumulated_frames);
+    let alpha = frames / (frames + history_frames);
+
+    let luminance = luminance(current);
+    let integrated_color = mix(previous.color, current, alpha);
+    let moments = mix(vec2<f32>(previous.moment_1, previous.moment_2), vec2<f32>(luminance, luminance * luminance), alpha);
+    let length = previous.length + 1.0;
+
+    var variance = max(moments.y - moments.x * moments.x, 0.0);
+    // Short histories don't have enough frames for the moments, the neighbourhood fills in
+    if length < 4.0 {
+        variance = max(variance, spatial_variance(global_id.xy, size));
+    }
+
+    history[pixel_index] = History(integrated_color, length, position, moments.x, normal, moments.y);
+    integrated[pixel_index] = vec4<f32>(integrated_color, variance);
+}
+
+// One iteration of the edge-avoiding à-trous wavelet filter (Dammertz et al. 2010), with the
+// variance guided color weights of SVGF (Schied et al. 2017)
+@compute @workgroup_size(8, 8)
+fn atrous(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let size = textureDimensions(output);
+    if any(global_id.xy >= size) {
+        return;
+    }
+
+    let pixel = vec2<i32>(global_id.xy);
+    let pixel_count = size.x * size.y;
+    let pixel_index = global_id.x + global_id.y * size.x;
+
+    let center = filter_input[pixel_index];
+    let center_luminance = luminance(center.rgb);
+    let normal = load_aov(AOV_NORMAL, pixel_index, pixel_count);
+    let depth = load_aov(AOV_DEPTH, pixel_index, pixel_count).x;
+    let albedo = load_aov(AOV_ALBEDO, pixel_index, pixel_count);
+    let depth_gradient = depth_gradient(pixel, size, depth);
+
+    // Luminance differences are measured in standard deviations of the noise
+    let luminance_scale = uniforms.color_phi * sqrt(blurred_variance(pixel, size)) + 1e-6;
+
+    // The B3 spline kernel from the center outwards
+    let kernel = array<f32, 3>(3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0);
+
+    var color_sum = vec3<f32>();
+    var variance_sum = 0.0;
+    var weight_sum = 0.0;
+    for (var y = -2; y <= 2; y++) {
+        for (var x = -2; x <= 2; x++) {
+            let offset = vec2<i32>(x, y) * i32(uniforms.step);
+            let tap = pixel + offset;
+            if any(tap < vec2<i32>()) || any(tap >= vec2<i32>(size)) {
+                continue;
+            }
+
+            let tap_index = u32(tap.x) + u32(tap.y) * size.x;
+            let sample = filter_input[tap_index];
+            var weight = kernel[abs(x)] * kernel[abs(y)];
+
+            if x != 0 || y != 0 {
+                let tap_normal = load_aov(AOV_NORMAL, tap_index, pixel_count);
+                let tap_depth = load_aov(AOV_DEPTH, tap_index, pixel_count).x;
+                let tap_albedo = load_aov(AOV_ALBEDO, tap_index, pixel_count);
+
+                let normal_weight = pow(max(dot(normal, tap_normal), 0.0), uniforms.normal_phi);
+                // Depth differences are measured against the change expected on a plane
+                let expected_depth = abs(dot(depth_gradient, vec2<f32>(offset)));
+                let depth_weight = exp(-abs(depth - tap_depth) / (uniforms.depth_phi * expected_depth + 1e-3 * depth + 1e-6));
+                let albedo_weight = exp(-distance(albedo, tap_albedo) / uniforms.albedo_phi);
+                let color_weight = exp(-abs(center_luminance - luminance(sample.rgb)) / luminance_scale);
+
+                weight *= normal_weight * depth_weight * albedo_weight * color_weight;
+            }
+
+            color_sum += sample.rgb * weight;
+            variance_sum += sample.a * weight * weight;
+            weight_sum += weight;
+        }
+    }
+
+    // The center always has a weight, so `weight_sum` isn't zero
+    let filtered = vec4<f32>(color_sum / weight_sum, variance_sum / (weight_sum * weight_sum));
+
+    if uniforms.write_target != 0u {
+        textureStore(output, global_id.xy, vec4<f32>(filtered.rgb, 1.0));
+    } else {
+        filter_output[pixel_index] = filtered;
+    }
+}
+
+// Reads an averaged AOV, `aov` has to be in `uniforms.aov_mask`
+fn load_aov(aov: u32, pixel_index: u32, pixel_count: u32) -> vec3<f32> {
+    let slot = countOneBits(uniforms.aov_mask & ((1u << aov) - 1u));
+    let value = aovs[slot * pixel_count + pixel_index];
+    return value.xyz / max(value.w, 1.0);
+}
+
+// The variance of the luminance in the 3x3 neighbourhood of `color`
+fn spatial_variance(pixel: vec2<u32>, size: vec2<u32>) -> f32 {
+    var moments = vec2<f32>();
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let tap = clamp(vec2<i32>(pixel) + vec2<i32>(x, y), vec2<i32>(), vec2<i32>(size) - 1);
+            let luminance = luminance(textureLoad(color, tap, 0).rgb);
+            moments += vec2<f32>(luminance, luminance * luminance);
+        }
+    }
+
+    moments /= 9.0;
+    return max(moments.y - moments.x * moments.x, 0.0);
+}
+
+// The variance of `filter_input` smoothed with a 3x3 gaussian, which makes the color weights more
+// stable
+fn blurred_variance(pixel: vec2<i32>, size: vec2<u32>) -> f32 {
+    let kernel = array<f32, 2>(1.0 / 2.0, 1.0 / 4.0);
+
+    var variance = 0.0;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let tap = clamp(pixel + vec2<i32>(x, y), vec2<i32>(), vec2<i32>(size) - 1);
+            variance += filter_input[u32(tap.x) + u32(tap.y) * size.x].a * kernel[abs(x)] * kernel[abs(y)];
+        }
+    }
+
+    return variance;
+}
+
+// The change in depth per pixel, from the neighbour on the side that changes the least so
+// silhouettes don't make it large
+fn depth_gradient(pixel: vec2<i32>, size: vec2<u32>, depth: f32) -> vec2<f32> {
+    let pixel_count = size.x * size.y;
+    let last = vec2<i32>(size) - 1;
+
+    var gradient = vec2<f32>();
+    for (var axis = 0; axis < 2; axis++) {
+        var direction = vec2<i32>();
+        direction[axis] = 1;
+
+        let next = clamp(pixel + direction, vec2<i32>(), last);
+        let previous = clamp(pixel - direction, vec2<i32>(), last);
+        let next_depth = load_aov(AOV_DEPTH, u32(next.x) + u32(next.y) * size.x, pixel_count).x;
+        let previous_depth = load_aov(AOV_DEPTH, u32(previous.x) + u32(previous.y) * size.x, pixel_count).x;
+
+        gradient[axis] = min(abs(next_depth - depth), abs(depth - previous_depth));
+    }
+
+    return gradient;
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
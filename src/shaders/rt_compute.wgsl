@@ -1,11 +1,55 @@
 struct Uniforms {
     view_inv: mat4x4<f32>,
     proj_inv: mat4x4<f32>,
+    sky_color: vec3<f32>,
+    samples_per_pixel: u32,
+    frame_index: u32,
+    max_bounces: u32,
+    seed: u32,
+    firefly_clamp: f32,
+    t_min: f32,
+    t_max: f32,
+    environment_rotation: f32,
+    environment_intensity: f32,
+    use_environment: u32,
+    // The number of directional lights at the start of `lights`
+    directional_light_count: u32,
+    light_bvh_node_count: u32,
+    // Non-zero if misses see `sky` instead of `sky_color`, ignored if `use_environment` is set
+    use_sky: u32,
+    sky: Sky,
+    // Zero for a pinhole camera
+    aperture_radius: f32,
+    // Distance along the view direction to the plane in focus
+    focus_distance: f32,
+    // The number of aperture blades, zero for a circular aperture
+    bokeh_blades: u32,
+    bokeh_rotation: f32,
+    projection: u32,
+    // Field of view across the image height in radians for fisheye projections
+    fisheye_fov: f32,
+    // Bit `AOV_*` is set for every AOV written to `aov_accumulation`
+    aov_mask: u32,
+    // The AOV shown in `output` instead of the color, or `NO_AOV`
+    view_aov: u32,
+};
+
+// The precomputed Preetham sky model
+struct Sky {
+    // Perez coefficients A to E for x, y and Y
+    perez: array<vec4<f32>, 5>,
+    zenith_scale: vec3<f32>,
+    sun_direction: vec3<f32>,
+    sun_cos_angular_radius: f32,
+    sun_radiance: vec3<f32>,
+    ground_radiance: vec3<f32>,
 };
 
 struct Vertex {
     pos: vec3<f32>,
+    u: f32,
     normal: vec3<f32>,
+    v: f32,
 };
 
 
@@ -18,12 +62,132 @@ struct Instance {
 
 struct Material {
     albedo: vec3<f32>,
+    metallic: f32,
     emissive: vec3<f32>,
-    emissive_strength: f32
+    emissive_strength: f32,
+    transmission_tint: vec3<f32>,
+    roughness: f32,
+    ior: f32,
+    transmission: f32,
+    // Indices into `textures`, `NO_TEXTURE` if the material doesn't use one
+    albedo_texture: u32,
+    emissive_texture: u32,
+    metallic_roughness_texture: u32,
+    normal_texture: u32,
+    opacity: f32,
+}
+
+struct Light {
+    position: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    cos_outer: f32,
+    emission: vec3<f32>,
+    cos_inner: f32,
+    axis_u: vec3<f32>,
+    axis_v: vec3<f32>,
+}
+
+struct EmissiveTriangle {
+    p0: vec3<f32>,
+    material_index: u32,
+    p1: vec3<f32>,
+    area: f32,
+    p2: vec3<f32>,
+    // Bit `i` is set if the path from the light BVH root takes the second child at depth `i`
+    bvh_trail: u32,
+    uv0: vec2<f32>,
+    uv1: vec2<f32>,
+    uv2: vec2<f32>,
+}
+
+struct LightBvhNode {
+    bounds_min: vec3<f32>,
+    phi: f32,
+    bounds_max: vec3<f32>,
+    cos_theta_o: f32,
+    direction: vec3<f32>,
+    cos_theta_e: f32,
+    // The second child of interior nodes or the light or emissive triangle index of leaves
+    index: u32,
+    flags: u32,
+}
+
+struct SampledEmitter {
+    index: u32,
+    is_triangle: bool,
+    // Probability of picking this emitter
+    pmf: f32,
+}
+
+struct Ray {
+    origin: vec3<f32>,
+    direction: vec3<f32>,
+}
+
+struct LightSample {
+    direction: vec3<f32>,
+    // Delta lights return the irradiance here and a pdf of 1
+    radiance: vec3<f32>,
+    // Solid angle pdf of `direction`
+    pdf: f32,
+    // How far the shadow ray has to go to reach the light
+    distance: f32,
+}
+
+// The AOVs of a single camera ray
+struct AovSample {
+    albedo: vec3<f32>,
+    normal: vec3<f32>,
+    depth: f32,
+    position: vec3<f32>,
+    instance_id: f32,
+    material_id: f32,
+    direct_diffuse: vec3<f32>,
+    indirect_diffuse: vec3<f32>,
+    emission: vec3<f32>,
+}
+
+struct BsdfSample {
+    direction: vec3<f32>,
+    // The BSDF times the cosine term divided by the pdf
+    weight: vec3<f32>,
+    pdf: f32,
 }
 
+const PI: f32 = 3.14159265;
+const NO_TEXTURE: u32 = 0xFFFFFFFFu;
+
+const LIGHT_POINT: u32 = 0u;
+const LIGHT_SPOT: u32 = 1u;
+const LIGHT_DIRECTIONAL: u32 = 2u;
+const LIGHT_RECT: u32 = 3u;
+const LIGHT_DISK: u32 = 4u;
+
+const PROJECTION_PERSPECTIVE: u32 = 0u;
+const PROJECTION_ORTHOGRAPHIC: u32 = 1u;
+const PROJECTION_EQUIRECTANGULAR: u32 = 2u;
+const PROJECTION_FISHEYE: u32 = 3u;
+
+const NO_AOV: u32 = 0xFFFFFFFFu;
+const AOV_ALBEDO: u32 = 0u;
+const AOV_NORMAL: u32 = 1u;
+const AOV_DEPTH: u32 = 2u;
+const AOV_POSITION: u32 = 3u;
+const AOV_INSTANCE_ID: u32 = 4u;
+const AOV_MATERIAL_ID: u32 = 5u;
+const AOV_DIRECT_DIFFUSE: u32 = 6u;
+const AOV_INDIRECT_DIFFUSE: u32 = 7u;
+const AOV_EMISSION: u32 = 8u;
+const AOV_COUNT: u32 = 9u;
+
+const NO_EMITTER: u32 = 0xFFFFFFFFu;
+const LIGHT_BVH_LEAF: u32 = 1u;
+const LIGHT_BVH_TRIANGLE: u32 = 2u;
+const LIGHT_BVH_TWO_SIDED: u32 = 4u;
+
 @group(0) @binding(0)
-var output: texture_storage_2d<rgba8unorm, write>;
+var output: texture_storage_2d<rgba16float, write>;
 
 @group(0) @binding(1)
 var<uniform> uniforms: Uniforms;
@@ -43,53 +207,320 @@ var<storage, read> instances: array<Instance>;
 @group(0) @binding(6)
 var acc_struct: acceleration_structure;
 
+// Running radiance sum in `xyz` and sample count in `w` for every pixel
+@group(0) @binding(7)
+var<storage, read_write> accumulation: array<vec4<f32>>;
+
+@group(0) @binding(8)
+var textures: binding_array<texture_2d<f32>>;
+
+@group(0) @binding(9)
+var texture_sampler: sampler;
+
+// Equirectangular radiance seen by rays that miss, only used if `uniforms.use_environment != 0`
+@group(0) @binding(10)
+var environment: texture_2d<f32>;
+
+// Marginal CDF over the environment rows followed by the conditional CDF of every row
+@group(0) @binding(11)
+var<storage, read> environment_distribution: array<f32>;
+
+// Directional lights first, then the lights referenced by the light BVH
+@group(0) @binding(12)
+var<storage, read> lights: array<Light>;
+
+// The world space triangles of emissive mesh objects
+@group(0) @binding(13)
+var<storage, read> emissive_triangles: array<EmissiveTriangle>;
+
+// The first emissive triangle of every TLAS instance, or `NO_EMITTER`
+@group(0) @binding(14)
+var<storage, read> instance_emitters: array<u32>;
+
+// Holds a placeholder if `uniforms.light_bvh_node_count` is zero
+@group(0) @binding(15)
+var<storage, read> light_bvh: array<LightBvhNode>;
+
+// One image for every AOV in `uniforms.aov_mask`, in the order of the `AOV_*` constants. Holds
+// running sums with the sample count in `w`, except for IDs that keep the first sample with a `w`
+// of one. A placeholder if no AOVs are enabled.
+@group(0) @binding(16)
+var<storage, read_write> aov_accumulation: array<vec4<f32>>;
+
 @compute @workgroup_size(8, 8)
 fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
     let target_size = textureDimensions(output);
+    if any(global_id.xy >= target_size) {
+        return;
+    }
 
     let pixel_center = vec2<f32>(global_id.xy) + vec2<f32>(0.5);
     let in_uv = pixel_center / vec2<f32>(target_size.xy);
     var d = in_uv * 2.0 - 1.0;
     d.y = -d.y; // Flip so objects with +y are on the top and -y are on the bottom
 
-    let origin = (uniforms.view_inv * vec4<f32>(0.0, 0.0, 0.0, 1.0)).xyz;
-    let temp = uniforms.proj_inv * vec4<f32>(d.x, d.y, 1.0, 1.0);
-    let direction = (uniforms.view_inv * vec4<f32>(normalize(temp.xyz), 0.0)).xyz;
+    let camera_ray = generate_camera_ray(d, in_uv, f32(target_size.x) / f32(target_size.y));
+    // The point every ray through this pixel converges on, in camera space
+    let focus_point = camera_ray.direction * (uniforms.focus_distance / -camera_ray.direction.z);
 
     let pixel_index = global_id.x + global_id.y * target_size.x;
-    var state = pixel_index;
+    // Decorrelate the random sequences of consecutive frames
+    var state = pcg_hash(pixel_index ^ pcg_hash(uniforms.frame_index + pcg_hash(uniforms.seed)));
 
     var color = vec3<f32>();
+    var aov_sum: AovSample;
+    aov_sum.instance_id = -1.0;
+    aov_sum.material_id = -1.0;
 
-    let rays_per_pixel = 100u;
+    let rays_per_pixel = uniforms.samples_per_pixel;
     for (var i: u32 = 0; i < rays_per_pixel; i++) {
-        color += trace_ray(origin, direction, &state);
+        // Pixels outside the image circle of a fisheye stay black
+        if all(camera_ray.direction == vec3<f32>()) {
+            continue;
+        }
+
+        // Thin lens model, rays start on the aperture and pass through the focus point
+        var lens_point = camera_ray.origin;
+        var ray_direction = camera_ray.direction;
+        if uniforms.aperture_radius > 0.0 && uniforms.projection == PROJECTION_PERSPECTIVE {
+            let u = vec2<f32>(pcg_random(&state), pcg_random(&state));
+            lens_point = vec3<f32>(sample_aperture(u) * uniforms.aperture_radius, 0.0);
+            ray_direction = normalize(focus_point - lens_point);
+        }
+        let origin = (uniforms.view_inv * vec4<f32>(lens_point, 1.0)).xyz;
+        let direction = (uniforms.view_inv * vec4<f32>(ray_direction, 0.0)).xyz;
+
+        var aov: AovSample;
+        aov.instance_id = -1.0;
+        aov.material_id = -1.0;
+        var sample = trace_ray(origin, direction, &state, &aov);
+
+        aov_sum.albedo += aov.albedo;
+        aov_sum.normal += aov.normal;
+        aov_sum.depth += aov.depth;
+        aov_sum.position += aov.position;
+        aov_sum.direct_diffuse += aov.direct_diffuse;
+        aov_sum.indirect_diffuse += aov.indirect_diffuse;
+        aov_sum.emission += aov.emission;
+        // Averaged IDs would be meaningless, so the first sample decides them
+        if i == 0u {
+            aov_sum.instance_id = aov.instance_id;
+            aov_sum.material_id = aov.material_id;
+        }
+
+        // Scale down overly bright samples instead of letting them show up as fireflies
+        let brightest = max(sample.x, max(sample.y, sample.z));
+        if uniforms.firefly_clamp > 0.0 && brightest > uniforms.firefly_clamp {
+            sample *= uniforms.firefly_clamp / brightest;
+        }
+
+        color += sample;
+    }
+
+    var accumulated = vec4<f32>(color, f32(rays_per_pixel));
+    if uniforms.frame_index > 0u {
+        accumulated += accumulation[pixel_index];
+    }
+    accumulation[pixel_index] = accumulated;
+
+    var display = accumulated.xyz / max(accumulated.w, 1.0);
+
+    let pixel_count = target_size.x * target_size.y;
+    for (var aov: u32 = 0; aov < AOV_COUNT; aov++) {
+        if (uniforms.aov_mask & (1u << aov)) == 0u {
+            continue;
+        }
+
+        // Bits of the enabled AOVs before this one
+        let slot = countOneBits(uniforms.aov_mask & ((1u << aov) - 1u));
+        let index = slot * pixel_count + pixel_index;
+
+        var value = aov_value(aov_sum, aov, f32(rays_per_pixel));
+        if uniforms.frame_index > 0u {
+            if aov == AOV_INSTANCE_ID || aov == AOV_MATERIAL_ID {
+                value = aov_accumulation[index];
+            } else {
+                value += aov_accumulation[index];
+            }
+        }
+        aov_accumulation[index] = value;
+
+        if aov == uniforms.view_aov {
+            display = visualize_aov(aov, value.xyz / max(value.w, 1.0));
+        }
     }
 
-    textureStore(output, global_id.xy, vec4<f32>(color / f32(rays_per_pixel), 1.0));
+    textureStore(output, global_id.xy, vec4<f32>(display, 1.0));
+}
+
+// The value of one AOV as it's stored in `aov_accumulation`
+fn aov_value(sum: AovSample, aov: u32, sample_count: f32) -> vec4<f32> {
+    switch aov {
+        case AOV_ALBEDO: {
+            return vec4<f32>(sum.albedo, sample_count);
+        }
+        case AOV_NORMAL: {
+            return vec4<f32>(sum.normal, sample_count);
+        }
+        case AOV_DEPTH: {
+            return vec4<f32>(sum.depth, 0.0, 0.0, sample_count);
+        }
+        case AOV_POSITION: {
+            return vec4<f32>(sum.position, sample_count);
+        }
+        case AOV_INSTANCE_ID: {
+            return vec4<f32>(sum.instance_id, 0.0, 0.0, 1.0);
+        }
+        case AOV_MATERIAL_ID: {
+            return vec4<f32>(sum.material_id, 0.0, 0.0, 1.0);
+        }
+        case AOV_DIRECT_DIFFUSE: {
+            return vec4<f32>(sum.direct_diffuse, sample_count);
+        }
+        case AOV_INDIRECT_DIFFUSE: {
+            return vec4<f32>(sum.indirect_diffuse, sample_count);
+        }
+        default: {
+            return vec4<f32>(sum.emission, sample_count);
+        }
+    }
 }
 
-fn trace_ray(initial_origin: vec3<f32>, initial_direction: vec3<f32>, state: ptr<function, u32>) -> vec3<f32> {
+// Turns an averaged AOV into a linear color for the window
+fn visualize_aov(aov: u32, value: vec3<f32>) -> vec3<f32> {
+    switch aov {
+        case AOV_NORMAL: {
+            // Decoded so the display shows the usual normal map colors
+            return pow(value * 0.5 + 0.5, vec3<f32>(2.2));
+        }
+        case AOV_DEPTH: {
+            // Half as bright at the focus distance, misses stay black
+            if value.x <= 0.0 {
+                return vec3<f32>();
+            }
+            return vec3<f32>(uniforms.focus_distance / (value.x + uniforms.focus_distance));
+        }
+        case AOV_POSITION: {
+            // A grid of unit cubes
+            return fract(value);
+        }
+        case AOV_INSTANCE_ID, AOV_MATERIAL_ID: {
+            if value.x < 0.0 {
+                return vec3<f32>();
+            }
+            let hash = pcg_hash(u32(value.x + 0.5));
+            return vec3<f32>(vec3<u32>(hash, hash >> 8u, hash >> 16u) & vec3<u32>(0xFFu)) / 255.0;
+        }
+        default: {
+            return value;
+        }
+    }
+}
+
+// The camera space ray through a point `d` in normalized device coordinates, which is at `uv` in
+// the image. Returns a zero direction for points that the projection doesn't cover.
+fn generate_camera_ray(d: vec2<f32>, uv: vec2<f32>, aspect: f32) -> Ray {
+    switch uniforms.projection {
+        case PROJECTION_ORTHOGRAPHIC: {
+            // Parallel rays that start on the plane of the camera
+            let near_point = uniforms.proj_inv * vec4<f32>(d.x, d.y, 0.0, 1.0);
+            return Ray(vec3<f32>(near_point.xy / near_point.w, 0.0), vec3<f32>(0.0, 0.0, -1.0));
+        }
+        case PROJECTION_EQUIRECTANGULAR: {
+            // Covers every direction with -Z in the center of the image, like environment maps
+            let phi = (uv.x - 0.5) * 2.0 * PI;
+            let theta = uv.y * PI;
+            let direction = vec3<f32>(sin(theta) * sin(phi), cos(theta), -sin(theta) * cos(phi));
+            return Ray(vec3<f32>(), direction);
+        }
+        case PROJECTION_FISHEYE: {
+            // Equidistant, the angle from the view direction grows linearly with the distance
+            // from the center. The image circle touches the top and bottom of the image.
+            let p = vec2<f32>(d.x * aspect, d.y);
+            let r = length(p);
+            if r > 1.0 {
+                return Ray(vec3<f32>(), vec3<f32>());
+            }
+
+            let theta = r * uniforms.fisheye_fov * 0.5;
+            var side = vec2<f32>();
+            if r > 0.0 {
+                side = p / r * sin(theta);
+            }
+            return Ray(vec3<f32>(), vec3<f32>(side, -cos(theta)));
+        }
+        default: {
+            let temp = uniforms.proj_inv * vec4<f32>(d.x, d.y, 1.0, 1.0);
+            return Ray(vec3<f32>(), normalize(temp.xyz));
+        }
+    }
+}
+
+// Uniformly distributed points on a unit disk or on the regular polygon of the aperture blades
+fn sample_aperture(u: vec2<f32>) -> vec2<f32> {
+    if uniforms.bokeh_blades == 0u {
+        let r = sqrt(u.x);
+        let phi = 2.0 * PI * u.y;
+        return vec2<f32>(r * cos(phi), r * sin(phi));
+    }
+
+    // Pick one of the equally sized triangles between the center and two neighbouring corners
+    let blades = f32(uniforms.bokeh_blades);
+    let scaled = u.x * blades;
+    let blade = min(floor(scaled), blades - 1.0);
+    let v = scaled - blade;
+
+    let angle_0 = uniforms.bokeh_rotation + 2.0 * PI * blade / blades;
+    let angle_1 = angle_0 + 2.0 * PI / blades;
+    let corner_0 = vec2<f32>(cos(angle_0), sin(angle_0));
+    let corner_1 = vec2<f32>(cos(angle_1), sin(angle_1));
+
+    // Uniform point in the triangle, the center is the third corner
+    let su = sqrt(v);
+    return corner_0 * su * (1.0 - u.y) + corner_1 * su * u.y;
+}
+
+// Returns the radiance arriving along the ray and fills in `aov`
+fn trace_ray(initial_origin: vec3<f32>, initial_direction: vec3<f32>, state: ptr<function, u32>, aov: ptr<function, AovSample>) -> vec3<f32> {
     var origin = initial_origin;
     var direction = initial_direction;
 
     var light = vec3<f32>();
     var color = vec3<f32>(1.0, 1.0, 1.0);
 
-    var rq: ray_query;
+    // The solid angle pdf of the BSDF sample that produced `direction`, used to weight emission
+    // that next-event estimation could also have found
+    var bsdf_pdf_of_direction = 0.0;
+    // The shading normal at `origin`, the light BVH needs it to reproduce its sampling probability
+    var origin_normal = vec3<f32>();
 
-    for (var i: u32 = 0; i < 10; i++) {
-        rayQueryInitialize(&rq, acc_struct, RayDesc(0u, 0xFFu, 0.001, 100.0, origin, direction));
-
-        if rayQueryProceed(&rq) {
-            // The closest hit is `Candidate` and not `Committed`
-            break;
-        }
+    let diffuse_aovs = (uniforms.aov_mask & ((1u << AOV_DIRECT_DIFFUSE) | (1u << AOV_INDIRECT_DIFFUSE))) != 0u;
+    // The part of `color` after the first bounce that the diffuse lobe of the first hit reflected
+    var diffuse_share = vec3<f32>();
 
-        let intersection = rayQueryGetCommittedIntersection(&rq);
+    for (var i: u32 = 0; i < uniforms.max_bounces; i++) {
+        let intersection = trace_closest(origin, direction, uniforms.t_max, state);
         if intersection.kind == RAY_QUERY_INTERSECTION_NONE {
-            // Sky color
-            light += (vec3<f32>(143.0, 210.0, 255.0) / 255.0) * color;
+            var mis_weight = 1.0;
+            if uniforms.use_environment != 0u && i > 0u {
+                mis_weight = power_heuristic(bsdf_pdf_of_direction, environment_pdf(direction));
+            }
+
+            var miss_light = sky_radiance(direction) * color * mis_weight;
+
+            // The sun disk is also found through shadow rays toward the directional sun light
+            if sun_can_be_hit() && dot(direction, uniforms.sky.sun_direction) >= uniforms.sky.sun_cos_angular_radius {
+                var sun_weight = 1.0;
+                if i > 0u {
+                    sun_weight = power_heuristic(bsdf_pdf_of_direction, sun_light_pdf());
+                }
+                miss_light += uniforms.sky.sun_radiance * color * sun_weight;
+            }
+
+            light += miss_light;
+            if diffuse_aovs {
+                add_diffuse_light(aov, miss_light * diffuse_share, i);
+            }
             break;
         }
 
@@ -109,20 +540,929 @@ fn trace_ray(initial_origin: vec3<f32>, initial_direction: vec3<f32>, state: ptr
         let local_pos = v_0.pos * bary.x + v_1.pos * bary.y + v_2.pos * bary.z;
         let pos = (intersection.object_to_world * vec4<f32>(local_pos, 1.0)).xyz;
         let normal_raw = v_0.normal * bary.x + v_1.normal * bary.y + v_2.normal * bary.z;
-        let normal = normalize((intersection.object_to_world * vec4<f32>(normal_raw, 0.0)).xyz);
+        // Normals are transformed with the inverse transpose so non-uniform scales don't skew them
+        let world_to_object = intersection.world_to_object;
+        let normal_matrix = transpose(mat3x3<f32>(world_to_object[0], world_to_object[1], world_to_object[2]));
+        let outward_normal = normalize(normal_matrix * normal_raw);
 
-        origin = pos;
-        direction = normalize(normal + random_direction(state)); // Lambertian distribution
+        let uv_0 = vec2<f32>(v_0.u, v_0.v);
+        let uv_1 = vec2<f32>(v_1.u, v_1.v);
+        let uv_2 = vec2<f32>(v_2.u, v_2.v);
+        let uv = uv_0 * bary.x + uv_1 * bary.y + uv_2 * bary.z;
+
+        let material = apply_textures(materials[instance.material_index], uv);
+
+        // Rays that hit the back face are leaving the object, the side comes from the triangle
+        // because interpolated normals can face the other way near silhouettes. Shading always
+        // happens with the normal facing the incoming ray, so the relative IOR is flipped for rays
+        // leaving the object.
+        let wo = -direction;
+        let face_normal = normal_matrix * cross(v_1.pos - v_0.pos, v_2.pos - v_0.pos);
+        let entering = dot(face_normal, wo) >= 0.0;
+        let facing_normal = select(-face_normal, face_normal, entering);
+        var normal = select(-outward_normal, outward_normal, entering);
+        normal = select(-normal, normal, dot(normal, facing_normal) >= 0.0);
+        let eta = select(1.0 / material.ior, material.ior, entering);
+
+        if material.normal_texture != NO_TEXTURE {
+            // Tangent frame from the UV derivatives of the triangle, V points down in the texture
+            let edge_1 = v_1.pos - v_0.pos;
+            let edge_2 = v_2.pos - v_0.pos;
+            let duv_1 = uv_1 - uv_0;
+            let duv_2 = uv_2 - uv_0;
+            let det = duv_1.x * duv_2.y - duv_2.x * duv_1.y;
+
+            if abs(det) > 1e-12 {
+                let dp_du = (edge_1 * duv_2.y - edge_2 * duv_1.y) / det;
+                let dp_dv = (edge_2 * duv_1.x - edge_1 * duv_2.x) / det;
+                let tangent = (intersection.object_to_world * vec4<f32>(dp_du, 0.0)).xyz;
+                let bitangent = (intersection.object_to_world * vec4<f32>(-dp_dv, 0.0)).xyz;
+
+                let mapped = textureSampleLevel(textures[material.normal_texture], texture_sampler, uv, 0.0).xyz * 2.0 - 1.0;
+                let mapped_normal = perturb_normal(normal, tangent, bitangent, mapped);
+
+                // Mapped normals facing away from the ray would make the BSDF sample nothing
+                if dot(mapped_normal, wo) > 0.0 {
+                    normal = mapped_normal;
+                }
+            }
+        }
+
+        // Emission that next-event estimation could also have sampled is weighted against it
+        var emission_weight = 1.0;
+        let first_emitter = instance_emitters[intersection.instance_index];
+        if i > 0u && first_emitter != NO_EMITTER && uniforms.light_bvh_node_count > 0u {
+            let triangle = emissive_triangles[first_emitter + intersection.primitive_index];
+            let area_normal = cross(triangle.p1 - triangle.p0, triangle.p2 - triangle.p0);
+            let cos_light = abs(dot(normalize(area_normal), direction));
+
+            if triangle.area > 0.0 && cos_light > 0.0 {
+                let selection_pmf = light_bvh_pmf(origin, origin_normal, triangle.bvh_trail);
+                let light_pdf = selection_pmf * intersection.t * intersection.t / (triangle.area * cos_light);
+                emission_weight = power_heuristic(bsdf_pdf_of_direction, light_pdf);
+            }
+        }
+
+        if i == 0u {
+            let camera_position = uniforms.view_inv[3].xyz;
+            let camera_forward = normalize(-uniforms.view_inv[2].xyz);
+
+            (*aov).albedo = material.albedo;
+            (*aov).normal = normal;
+            (*aov).depth = dot(pos - camera_position, camera_forward);
+            (*aov).position = pos;
+            (*aov).instance_id = f32(intersection.instance_index);
+            (*aov).material_id = f32(instance.material_index);
+        }
+
+        let emitted = material.emissive * material.emissive_strength * color * emission_weight;
+        light += emitted;
+        if i == 0u {
+            (*aov).emission = emitted;
+        } else if diffuse_aovs {
+            add_diffuse_light(aov, emitted * diffuse_share, i);
+        }
+
+        // The last bounce can't add light that arrives after another bounce
+        if uniforms.use_environment != 0u && i + 1u < uniforms.max_bounces {
+            let light_sample = sample_environment(vec2<f32>(pcg_random(state), pcg_random(state)));
+            let bsdf = eval_bsdf(material, normal, wo, light_sample.direction, eta);
+
+            if light_sample.pdf > 0.0 && any(bsdf > vec3<f32>()) && !is_occluded(pos, light_sample.direction, light_sample.distance, state) {
+                let mis_weight = power_heuristic(light_sample.pdf, bsdf_pdf(material, normal, wo, light_sample.direction, eta));
+                let contribution = color * bsdf * light_sample.radiance * mis_weight / light_sample.pdf;
+                light += contribution;
+                if diffuse_aovs {
+                    let share = select(diffuse_share, diffuse_fraction(material, normal, wo, light_sample.direction, eta), i == 0u);
+                    add_diffuse_light(aov, contribution * share, i + 1u);
+                }
+            }
+        }
+
+        // Lights and emissive triangles picked by their estimated contribution to this point
+        if uniforms.light_bvh_node_count > 0u && i + 1u < uniforms.max_bounces {
+            let emitter = sample_light_bvh(pos, normal, pcg_random(state));
+            let u = vec2<f32>(pcg_random(state), pcg_random(state));
 
-        let material = materials[instance.material_index];
+            var light_sample: LightSample;
+            if emitter.is_triangle {
+                light_sample = sample_emissive_triangle(emissive_triangles[emitter.index], pos, u);
+            } else {
+                light_sample = sample_light(lights[emitter.index], pos, u);
+            }
+            light_sample.pdf *= emitter.pmf;
+            let bsdf = eval_bsdf(material, normal, wo, light_sample.direction, eta);
 
-        light += material.emissive * material.emissive_strength * color;
-        color *= material.albedo;
+            if light_sample.pdf > 0.0 && any(bsdf > vec3<f32>()) && !is_occluded(pos, light_sample.direction, light_sample.distance, state) {
+                // Explicit lights can't be hit by rays, so shadow rays are the only way to find them
+                var mis_weight = 1.0;
+                if emitter.is_triangle {
+                    mis_weight = power_heuristic(light_sample.pdf, bsdf_pdf(material, normal, wo, light_sample.direction, eta));
+                }
+                let contribution = color * bsdf * light_sample.radiance * mis_weight / light_sample.pdf;
+                light += contribution;
+                if diffuse_aovs {
+                    let share = select(diffuse_share, diffuse_fraction(material, normal, wo, light_sample.direction, eta), i == 0u);
+                    add_diffuse_light(aov, contribution * share, i + 1u);
+                }
+            }
+        }
+
+        if uniforms.directional_light_count > 0u && i + 1u < uniforms.max_bounces {
+            let light_index = min(u32(pcg_random(state) * f32(uniforms.directional_light_count)), uniforms.directional_light_count - 1u);
+            let light_sample = sample_light(lights[light_index], pos, vec2<f32>(pcg_random(state), pcg_random(state)));
+            let bsdf = eval_bsdf(material, normal, wo, light_sample.direction, eta);
+
+            if light_sample.pdf > 0.0 && any(bsdf > vec3<f32>()) && !is_occluded(pos, light_sample.direction, light_sample.distance, state) {
+                let selection_pmf = 1.0 / f32(uniforms.directional_light_count);
+                // The sun is the first directional light and the only one rays can hit
+                var mis_weight = 1.0;
+                if light_index == 0u && sun_can_be_hit() {
+                    mis_weight = power_heuristic(light_sample.pdf * selection_pmf, bsdf_pdf(material, normal, wo, light_sample.direction, eta));
+                }
+                let contribution = color * bsdf * light_sample.radiance * mis_weight / (light_sample.pdf * selection_pmf);
+                light += contribution;
+                if diffuse_aovs {
+                    let share = select(diffuse_share, diffuse_fraction(material, normal, wo, light_sample.direction, eta), i == 0u);
+                    add_diffuse_light(aov, contribution * share, i + 1u);
+                }
+            }
+        }
+
+        let bsdf_sample = sample_bsdf(material, normal, wo, eta, state);
+        if bsdf_sample.pdf <= 0.0 {
+            break;
+        }
+
+        if i == 0u && diffuse_aovs {
+            diffuse_share = diffuse_fraction(material, normal, wo, bsdf_sample.direction, eta);
+        }
+
+        origin = pos;
+        direction = bsdf_sample.direction;
+        color *= bsdf_sample.weight;
+        bsdf_pdf_of_direction = bsdf_sample.pdf;
+        origin_normal = normal;
     }
 
     return light;
 }
 
+// Whether rays that miss the scene can see the sun disk, it's dark if the sun is a single direction
+fn sun_can_be_hit() -> bool {
+    return uniforms.use_environment == 0u && uniforms.use_sky != 0u && any(uniforms.sky.sun_radiance > vec3<f32>());
+}
+
+// The solid angle pdf of shadow rays toward the sun, picked among the directional lights
+fn sun_light_pdf() -> f32 {
+    let solid_angle = 2.0 * PI * (1.0 - uniforms.sky.sun_cos_angular_radius);
+    return 1.0 / (f32(uniforms.directional_light_count) * solid_angle);
+}
+
+// Sorts light reflected diffusely by the first hit into the AOVs, light that was reflected once
+// before reaching the camera arrived at the first hit directly
+fn add_diffuse_light(aov: ptr<function, AovSample>, light: vec3<f32>, reflections: u32) {
+    if reflections <= 1u {
+        (*aov).direct_diffuse += light;
+    } else {
+        (*aov).indirect_diffuse += light;
+    }
+}
+
+fn sky_radiance(direction: vec3<f32>) -> vec3<f32> {
+    if uniforms.use_environment == 0u {
+        if uniforms.use_sky != 0u {
+            return physical_sky_radiance(direction);
+        }
+        return uniforms.sky_color;
+    }
+
+    let size = textureDimensions(environment);
+    let uv = environment_uv(direction);
+    let texel = min(vec2<u32>(uv * vec2<f32>(size)), size - 1u);
+
+    return textureLoad(environment, texel, 0).rgb * uniforms.environment_intensity;
+}
+
+// The Preetham sky without the sun, below the horizon is a uniformly lit ground
+fn physical_sky_radiance(direction: vec3<f32>) -> vec3<f32> {
+    let d = normalize(direction);
+    if d.y <= 0.0 {
+        return uniforms.sky.ground_radiance;
+    }
+
+    let gamma = acos(clamp(dot(d, uniforms.sky.sun_direction), -1.0, 1.0));
+    let cos_gamma = cos(gamma);
+    let p = uniforms.sky.perez;
+    let distribution = (1.0 + p[0].xyz * exp(p[1].xyz / max(d.y, 0.001))) * (1.0 + p[2].xyz * exp(p[3].xyz * gamma) + p[4].xyz * cos_gamma * cos_gamma);
+    let x_y_luminance = uniforms.sky.zenith_scale * distribution;
+    if x_y_luminance.y <= 0.0 {
+        return vec3<f32>();
+    }
+
+    let xyz = vec3<f32>(x_y_luminance.x, x_y_luminance.y, 1.0 - x_y_luminance.x - x_y_luminance.y) * (x_y_luminance.z / x_y_luminance.y);
+    let rgb = mat3x3<f32>(
+        vec3<f32>(3.2406, -0.9689, 0.0557),
+        vec3<f32>(-1.5372, 1.8758, -0.2040),
+        vec3<f32>(-0.4986, 0.0415, 1.0570),
+    ) * xyz;
+
+    return max(rgb, vec3<f32>());
+}
+
+// Maps a world space direction to equirectangular coordinates, with -Z in the center of the image
+// and +Y at the top. The environment is turned counterclockwise around +Y by its rotation.
+fn environment_uv(direction: vec3<f32>) -> vec2<f32> {
+    let d = normalize(direction);
+    let phi = atan2(d.x, -d.z) + uniforms.environment_rotation;
+    let theta = acos(clamp(d.y, -1.0, 1.0));
+
+    return vec2<f32>(fract(0.5 + phi / (2.0 * PI)), theta / PI);
+}
+
+// Maps equirectangular coordinates back to a world space direction, the inverse of `environment_uv`
+fn environment_direction(uv: vec2<f32>) -> vec3<f32> {
+    let phi = (uv.x - 0.5) * 2.0 * PI - uniforms.environment_rotation;
+    let theta = uv.y * PI;
+
+    return vec3<f32>(sin(theta) * sin(phi), cos(theta), -sin(theta) * cos(phi));
+}
+
+// Returns the index `i` in `0..count` of the interval `cdf[offset + i]..cdf[offset + i + 1]` that
+// contains `value`
+fn find_interval(offset: u32, count: u32, value: f32) -> u32 {
+    var low = 0u;
+    var high = count;
+    while low + 1u < high {
+        let middle = (low + high) / 2u;
+        if environment_distribution[offset + middle] <= value {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+
+    return low;
+}
+
+// Picks a direction proportional to the environment luminance with the CDFs built on the CPU
+fn sample_environment(u: vec2<f32>) -> LightSample {
+    let size = textureDimensions(environment);
+
+    let row = find_interval(0u, size.y, u.y);
+    let row_start = environment_distribution[row];
+    let row_pdf = environment_distribution[row + 1u] - row_start;
+
+    let conditional_offset = size.y + 1u + row * (size.x + 1u);
+    let column = find_interval(conditional_offset, size.x, u.x);
+    let column_start = environment_distribution[conditional_offset + column];
+    let column_pdf = environment_distribution[conditional_offset + column + 1u] - column_start;
+
+    if row_pdf <= 0.0 || column_pdf <= 0.0 {
+        return LightSample(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(), 0.0, 0.0);
+    }
+
+    // Reuse the remainder of the random numbers to place the sample within the texel
+    let offset = vec2<f32>((u.x - column_start) / column_pdf, (u.y - row_start) / row_pdf);
+    let uv = (vec2<f32>(f32(column), f32(row)) + saturate(offset)) / vec2<f32>(size);
+    let sin_theta = sin(uv.y * PI);
+    if sin_theta <= 0.0 {
+        return LightSample(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(), 0.0, 0.0);
+    }
+
+    let radiance = textureLoad(environment, vec2<u32>(column, row), 0).rgb * uniforms.environment_intensity;
+    // The uv to solid angle Jacobian of the equirectangular mapping is `2 * PI^2 * sin(theta)`
+    let pdf = row_pdf * column_pdf * f32(size.x * size.y) / (2.0 * PI * PI * sin_theta);
+
+    return LightSample(environment_direction(uv), radiance, pdf, uniforms.t_max);
+}
+
+// Picks a direction from `pos` toward a point on `light`
+fn sample_light(light: Light, pos: vec3<f32>, u: vec2<f32>) -> LightSample {
+    let no_sample = LightSample(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(), 0.0, 0.0);
+
+    switch light.kind {
+        case LIGHT_POINT, LIGHT_SPOT: {
+            let to_light = light.position - pos;
+            let distance_sq = dot(to_light, to_light);
+            if distance_sq <= 0.0 {
+                return no_sample;
+            }
+
+            let direction = to_light * inverseSqrt(distance_sq);
+            var falloff = 1.0;
+            if light.kind == LIGHT_SPOT {
+                falloff = smoothstep(light.cos_outer, light.cos_inner, dot(-direction, light.direction));
+            }
+
+            return LightSample(direction, light.emission * falloff / distance_sq, 1.0, sqrt(distance_sq));
+        }
+        case LIGHT_DIRECTIONAL: {
+            // Sharp shadows from a single direction if the angular radius is zero
+            if light.cos_outer >= 1.0 {
+                return LightSample(-light.direction, light.emission, 1.0, uniforms.t_max);
+            }
+
+            // Uniform directions in the cone toward the light, with the radiance that gives the
+            // requested irradiance at normal incidence
+            let cos_theta = 1.0 - u.x * (1.0 - light.cos_outer);
+            let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+            let phi = 2.0 * PI * u.y;
+            let direction = orthonormal_basis(-light.direction) * vec3<f32>(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+
+            let solid_angle = 2.0 * PI * (1.0 - light.cos_outer);
+            let radiance = light.emission / (PI * (1.0 - light.cos_outer * light.cos_outer));
+
+            return LightSample(direction, radiance, 1.0 / solid_angle, uniforms.t_max);
+        }
+        default: {
+            var point: vec3<f32>;
+            var area: f32;
+            if light.kind == LIGHT_RECT {
+                point = light.position + light.axis_u * (u.x * 2.0 - 1.0) + light.axis_v * (u.y * 2.0 - 1.0);
+                area = 4.0 * length(light.axis_u) * length(light.axis_v);
+            } else {
+                let radius = sqrt(u.x);
+                let phi = 2.0 * PI * u.y;
+                point = light.position + (light.axis_u * cos(phi) + light.axis_v * sin(phi)) * radius;
+                area = PI * length(light.axis_u) * length(light.axis_v);
+            }
+
+            let to_light = point - pos;
+            let distance_sq = dot(to_light, to_light);
+            let direction = to_light * inverseSqrt(distance_sq);
+            // Area lights only emit from their front side
+            let cos_light = dot(-direction, light.direction);
+            if distance_sq <= 0.0 || cos_light <= 0.0 || area <= 0.0 {
+                return no_sample;
+            }
+
+            // Converts the uniform area pdf to solid angle
+            let pdf = distance_sq / (area * cos_light);
+
+            return LightSample(direction, light.emission, pdf, sqrt(distance_sq));
+        }
+    }
+}
+
+// The solid angle pdf of `sample_environment` returning `direction`
+fn environment_pdf(direction: vec3<f32>) -> f32 {
+    let size = textureDimensions(environment);
+    let uv = environment_uv(direction);
+    let texel = min(vec2<u32>(uv * vec2<f32>(size)), size - 1u);
+
+    let row_pdf = environment_distribution[texel.y + 1u] - environment_distribution[texel.y];
+    let conditional_offset = size.y + 1u + texel.y * (size.x + 1u) + texel.x;
+    let column_pdf = environment_distribution[conditional_offset + 1u] - environment_distribution[conditional_offset];
+
+    let sin_theta = sqrt(max(1.0 - direction.y * direction.y, 0.0));
+    if sin_theta <= 0.0 {
+        return 0.0;
+    }
+
+    return row_pdf * column_pdf * f32(size.x * size.y) / (2.0 * PI * PI * sin_theta);
+}
+
+// Picks a uniformly distributed point on an emissive triangle
+fn sample_emissive_triangle(triangle: EmissiveTriangle, pos: vec3<f32>, u: vec2<f32>) -> LightSample {
+    let no_sample = LightSample(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(), 0.0, 0.0);
+
+    let su = sqrt(u.x);
+    let bary = vec3<f32>(1.0 - su, u.y * su, su * (1.0 - u.y));
+    let point = triangle.p0 * bary.x + triangle.p1 * bary.y + triangle.p2 * bary.z;
+    let uv = triangle.uv0 * bary.x + triangle.uv1 * bary.y + triangle.uv2 * bary.z;
+
+    let to_light = point - pos;
+    let distance_sq = dot(to_light, to_light);
+    if distance_sq <= 0.0 || triangle.area <= 0.0 {
+        return no_sample;
+    }
+    let direction = to_light * inverseSqrt(distance_sq);
+
+    // Emissive surfaces emit from both sides
+    let cos_light = abs(dot(normalize(cross(triangle.p1 - triangle.p0, triangle.p2 - triangle.p0)), direction));
+    if cos_light <= 0.0 {
+        return no_sample;
+    }
+
+    let material = apply_textures(materials[triangle.material_index], uv);
+    let radiance = material.emissive * material.emissive_strength;
+    // Converts the uniform area pdf to solid angle
+    let pdf = distance_sq / (triangle.area * cos_light);
+
+    return LightSample(direction, radiance, pdf, sqrt(distance_sq));
+}
+
+// cos(max(0, a - b)) and sin(max(0, a - b)) from the sines and cosines of `a` and `b`
+fn cos_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b {
+        return 1.0;
+    }
+    return cos_a * cos_b + sin_a * sin_b;
+}
+
+fn sin_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b {
+        return 0.0;
+    }
+    return sin_a * cos_b - cos_a * sin_b;
+}
+
+// A conservative estimate of the light that the emitters below `node` send to `pos`, which has
+// the normal `n` (pbrt-v4's `LightBounds::Importance`)
+fn light_bvh_importance(node: LightBvhNode, pos: vec3<f32>, n: vec3<f32>) -> f32 {
+    let center = (node.bounds_min + node.bounds_max) * 0.5;
+    let to_pos = pos - center;
+    let distance_sq = max(dot(to_pos, to_pos), length(node.bounds_max - node.bounds_min) * 0.5);
+    if distance_sq <= 0.0 {
+        return node.phi;
+    }
+    let wi = to_pos * inverseSqrt(max(dot(to_pos, to_pos), 1e-12));
+
+    // Angle between the cone axis and the direction toward `pos`
+    var cos_theta_w = dot(node.direction, wi);
+    if (node.flags & LIGHT_BVH_TWO_SIDED) != 0u {
+        cos_theta_w = abs(cos_theta_w);
+    }
+    let sin_theta_w = sqrt(max(1.0 - cos_theta_w * cos_theta_w, 0.0));
+
+    // Half angle of the bounding sphere of the node seen from `pos`
+    let radius_sq = dot(node.bounds_max - center, node.bounds_max - center);
+    var cos_theta_b = -1.0;
+    if dot(to_pos, to_pos) > radius_sq {
+        cos_theta_b = sqrt(max(1.0 - radius_sq / dot(to_pos, to_pos), 0.0));
+    }
+    let sin_theta_b = sqrt(max(1.0 - cos_theta_b * cos_theta_b, 0.0));
+
+    // The smallest angle between `wi` and any emitter normal in the node
+    let sin_theta_o = sqrt(max(1.0 - node.cos_theta_o * node.cos_theta_o, 0.0));
+    let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, node.cos_theta_o);
+    let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, node.cos_theta_o);
+    let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+    if cos_theta_p <= node.cos_theta_e {
+        return 0.0;
+    }
+
+    var importance = node.phi * cos_theta_p / distance_sq;
+
+    // Incident cosine at `pos`, both hemispheres count since surfaces can transmit
+    if any(n != vec3<f32>()) {
+        let cos_theta_i = abs(dot(wi, n));
+        let sin_theta_i = sqrt(max(1.0 - cos_theta_i * cos_theta_i, 0.0));
+        importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+    }
+
+    return max(importance, 0.0);
+}
+
+// Walks down the light BVH, choosing children by their importance
+fn sample_light_bvh(pos: vec3<f32>, n: vec3<f32>, random: f32) -> SampledEmitter {
+    var u = random;
+    var node_index = 0u;
+    var pmf = 1.0;
+
+    loop {
+        let node = light_bvh[node_index];
+        if (node.flags & LIGHT_BVH_LEAF) != 0u {
+            return SampledEmitter(node.index, (node.flags & LIGHT_BVH_TRIANGLE) != 0u, pmf);
+        }
+
+        let first_importance = light_bvh_importance(light_bvh[node_index + 1u], pos, n);
+        let second_importance = light_bvh_importance(light_bvh[node.index], pos, n);
+        if first_importance + second_importance <= 0.0 {
+            return SampledEmitter(0u, false, 0.0);
+        }
+
+        let first_probability = first_importance / (first_importance + second_importance);
+        if u < first_probability {
+            u = min(u / first_probability, 0.99999994);
+            node_index += 1u;
+            pmf *= first_probability;
+        } else {
+            u = min((u - first_probability) / (1.0 - first_probability), 0.99999994);
+            node_index = node.index;
+            pmf *= 1.0 - first_probability;
+        }
+    }
+
+    return SampledEmitter(0u, false, 0.0);
+}
+
+// The probability of `sample_light_bvh` picking the emitter at the end of `trail`
+fn light_bvh_pmf(pos: vec3<f32>, n: vec3<f32>, trail: u32) -> f32 {
+    var bits = trail;
+    var node_index = 0u;
+    var pmf = 1.0;
+
+    loop {
+        let node = light_bvh[node_index];
+        if (node.flags & LIGHT_BVH_LEAF) != 0u {
+            return pmf;
+        }
+
+        let first_importance = light_bvh_importance(light_bvh[node_index + 1u], pos, n);
+        let second_importance = light_bvh_importance(light_bvh[node.index], pos, n);
+        if first_importance + second_importance <= 0.0 {
+            return 0.0;
+        }
+
+        if (bits & 1u) != 0u {
+            pmf *= second_importance / (first_importance + second_importance);
+            node_index = node.index;
+        } else {
+            pmf *= first_importance / (first_importance + second_importance);
+            node_index += 1u;
+        }
+        bits >>= 1u;
+    }
+
+    return 0.0;
+}
+
+// Returns true if anything is hit between `origin` and `distance` along `direction`
+fn is_occluded(origin: vec3<f32>, direction: vec3<f32>, distance: f32, state: ptr<function, u32>) -> bool {
+    // Stop short of the light so area lights that sit on geometry aren't shadowed by it
+    let t_max = min(distance * (1.0 - 1e-4), uniforms.t_max);
+
+    var shadow_rq: ray_query;
+    rayQueryInitialize(&shadow_rq, acc_struct, RayDesc(RAY_FLAG_TERMINATE_ON_FIRST_HIT, 0xFFu, uniforms.t_min, t_max, origin, direction));
+    rayQueryProceed(&shadow_rq);
+
+    // Any opaque surface blocks the light, cut-outs have to be passed in order
+    let intersection = rayQueryGetCommittedIntersection(&shadow_rq);
+    if intersection.kind == RAY_QUERY_INTERSECTION_NONE {
+        return false;
+    }
+    if hit_opacity(intersection) >= 1.0 {
+        return true;
+    }
+
+    return trace_closest(origin, direction, t_max, state).kind != RAY_QUERY_INTERSECTION_NONE;
+}
+
+// Returns the closest hit that stops the ray, rays pass through cut-out surfaces with the chance
+// given by their opacity
+fn trace_closest(origin: vec3<f32>, direction: vec3<f32>, t_max: f32, state: ptr<function, u32>) -> RayIntersection {
+    var rq: ray_query;
+    var t_min = uniforms.t_min;
+    var intersection: RayIntersection;
+
+    loop {
+        rayQueryInitialize(&rq, acc_struct, RayDesc(0u, 0xFFu, t_min, t_max, origin, direction));
+        rayQueryProceed(&rq);
+
+        intersection = rayQueryGetCommittedIntersection(&rq);
+        if intersection.kind == RAY_QUERY_INTERSECTION_NONE {
+            break;
+        }
+
+        let opacity = hit_opacity(intersection);
+        if opacity >= 1.0 || pcg_random(state) < opacity {
+            break;
+        }
+
+        t_min = intersection.t + uniforms.t_min;
+    }
+
+    return intersection;
+}
+
+fn hit_opacity(intersection: RayIntersection) -> f32 {
+    return materials[instances[intersection.instance_custom_data].material_index].opacity;
+}
+
+// Multiple importance sampling weight of a sample from the strategy with `pdf` (Veach 1997)
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let pdf_sq = pdf * pdf;
+    let sum = pdf_sq + other_pdf * other_pdf;
+    if sum <= 0.0 {
+        return 0.0;
+    }
+
+    return pdf_sq / sum;
+}
+
+// Multiplies the material factors with its textures, following the glTF channel layout
+fn apply_textures(base: Material, uv: vec2<f32>) -> Material {
+    var material = base;
+
+    if material.albedo_texture != NO_TEXTURE {
+        let albedo = textureSampleLevel(textures[material.albedo_texture], texture_sampler, uv, 0.0).rgb;
+        material.albedo *= albedo;
+        material.transmission_tint *= albedo;
+    }
+    if material.emissive_texture != NO_TEXTURE {
+        material.emissive *= textureSampleLevel(textures[material.emissive_texture], texture_sampler, uv, 0.0).rgb;
+    }
+    if material.metallic_roughness_texture != NO_TEXTURE {
+        let metallic_roughness = textureSampleLevel(textures[material.metallic_roughness_texture], texture_sampler, uv, 0.0);
+        material.roughness *= metallic_roughness.g;
+        material.metallic *= metallic_roughness.b;
+    }
+
+    return material;
+}
+
+// Transforms a tangent space normal into world space with an orthonormalized tangent frame
+fn perturb_normal(n: vec3<f32>, tangent: vec3<f32>, bitangent: vec3<f32>, mapped: vec3<f32>) -> vec3<f32> {
+    let t = normalize(tangent - n * dot(n, tangent));
+    // Keep the handedness of the UV mapping, which flips for mirrored UVs and back faces
+    let b = cross(n, t) * select(-1.0, 1.0, dot(cross(n, t), bitangent) >= 0.0);
+
+    return normalize(t * mapped.x + b * mapped.y + n * mapped.z);
+}
+
+// Metallic-roughness BSDF: a Lambertian diffuse base under a GGX specular layer, blended with a
+// rough GGX dielectric for transmissive materials.
+//
+// `n` always faces `wo`. `eta` is the IOR on the far side of the surface relative to the IOR on the
+// side of `wo`, it's only used by the transmissive lobe.
+
+fn roughness_to_alpha(roughness: f32) -> f32 {
+    return max(roughness * roughness, 0.001);
+}
+
+fn specular_f0(material: Material) -> vec3<f32> {
+    let dielectric_f0 = pow((material.ior - 1.0) / (material.ior + 1.0), 2.0);
+    return mix(vec3<f32>(dielectric_f0), material.albedo, material.metallic);
+}
+
+// Fresnel reflectance of a smooth dielectric interface, returns 1 on total internal reflection
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin_t_sq = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin_t_sq >= 1.0 {
+        return 1.0;
+    }
+
+    let cos_t = sqrt(1.0 - sin_t_sq);
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+
+    return 0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular);
+}
+
+// The share of the BSDF that is handled by the transmissive dielectric lobe
+fn transmission_weight(material: Material) -> f32 {
+    return material.transmission * (1.0 - material.metallic);
+}
+
+fn fresnel_schlick(f0: vec3<f32>, cos_theta: f32) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(1.0 - saturate(cos_theta), 5.0);
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// GGX / Trowbridge-Reitz normal distribution
+fn ggx_d(n_dot_h: f32, alpha_sq: f32) -> f32 {
+    let d = n_dot_h * n_dot_h * (alpha_sq - 1.0) + 1.0;
+    return alpha_sq / (PI * d * d);
+}
+
+// Smith masking function for GGX
+fn smith_g1(n_dot_x: f32, alpha_sq: f32) -> f32 {
+    return 2.0 * n_dot_x / (n_dot_x + sqrt(alpha_sq + (1.0 - alpha_sq) * n_dot_x * n_dot_x));
+}
+
+// The probability of sampling the specular lobe instead of the diffuse lobe
+fn specular_probability(material: Material, n_dot_v: f32) -> f32 {
+    let specular = luminance(fresnel_schlick(specular_f0(material), n_dot_v));
+    let diffuse = luminance(material.albedo) * (1.0 - material.metallic) * (1.0 - specular);
+    let total = specular + diffuse;
+
+    // Black dielectrics without reflectance (F0 = 0) reflect nothing at normal incidence, either
+    // lobe is as good as the other but dividing would give NaN
+    return select(0.5, specular / total, total > 0.0);
+}
+
+// Builds an orthonormal basis around `n` (Duff et al. 2017), `n` becomes the z axis
+fn orthonormal_basis(n: vec3<f32>) -> mat3x3<f32> {
+    let s = select(-1.0, 1.0, n.z >= 0.0);
+    let a = -1.0 / (s + n.z);
+    let b = n.x * n.y * a;
+
+    return mat3x3<f32>(
+        vec3<f32>(1.0 + s * n.x * n.x * a, s * b, -s * n.x),
+        vec3<f32>(b, s + n.y * n.y * a, -n.y),
+        n,
+    );
+}
+
+// Samples a microfacet normal from the distribution of visible normals (Heitz 2018)
+// `v` is the view direction in the local shading frame
+fn sample_ggx_vndf(v: vec3<f32>, alpha: f32, u: vec2<f32>) -> vec3<f32> {
+    let vh = normalize(vec3<f32>(alpha * v.x, alpha * v.y, v.z));
+
+    let len_sq = vh.x * vh.x + vh.y * vh.y;
+    let t1 = select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(-vh.y, vh.x, 0.0) * inverseSqrt(len_sq), len_sq > 0.0);
+    let t2 = cross(vh, t1);
+
+    let r = sqrt(u.x);
+    let phi = 2.0 * PI * u.y;
+    let p1 = r * cos(phi);
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * sqrt(1.0 - p1 * p1) + s * r * sin(phi);
+
+    let nh = p1 * t1 + p2 * t2 + sqrt(max(0.0, 1.0 - p1 * p1 - p2 * p2)) * vh;
+
+    return normalize(vec3<f32>(alpha * nh.x, alpha * nh.y, max(0.0, nh.z)));
+}
+
+// Evaluates the BSDF multiplied by the cosine term for light arriving from `wi` and leaving to `wo`
+fn eval_bsdf(material: Material, n: vec3<f32>, wo: vec3<f32>, wi: vec3<f32>, eta: f32) -> vec3<f32> {
+    let transmission = transmission_weight(material);
+
+    return mix(eval_opaque(material, n, wo, wi), eval_dielectric(material, n, wo, wi, eta), transmission);
+}
+
+// The pdf of `sample_bsdf` returning `wi`
+fn bsdf_pdf(material: Material, n: vec3<f32>, wo: vec3<f32>, wi: vec3<f32>, eta: f32) -> f32 {
+    let transmission = transmission_weight(material);
+
+    return mix(opaque_pdf(material, n, wo, wi), dielectric_pdf(material, n, wo, wi, eta), transmission);
+}
+
+fn sample_bsdf(material: Material, n: vec3<f32>, wo: vec3<f32>, eta: f32, state: ptr<function, u32>) -> BsdfSample {
+    var wi: vec3<f32>;
+    if pcg_random(state) < transmission_weight(material) {
+        wi = sample_dielectric(material, n, wo, eta, state);
+    } else {
+        wi = sample_opaque(material, n, wo, state);
+    }
+
+    let pdf = bsdf_pdf(material, n, wo, wi, eta);
+    if pdf <= 0.0 {
+        return BsdfSample(wi, vec3<f32>(), 0.0);
+    }
+
+    return BsdfSample(wi, eval_bsdf(material, n, wo, wi, eta) / pdf, pdf);
+}
+
+fn eval_opaque(material: Material, n: vec3<f32>, wo: vec3<f32>, wi: vec3<f32>) -> vec3<f32> {
+    let n_dot_v = dot(n, wo);
+    let n_dot_l = dot(n, wi);
+    if n_dot_v <= 0.0 || n_dot_l <= 0.0 {
+        return vec3<f32>();
+    }
+
+    let h = normalize(wo + wi);
+    let alpha = roughness_to_alpha(material.roughness);
+    let alpha_sq = alpha * alpha;
+
+    let fresnel = fresnel_schlick(specular_f0(material), dot(wo, h));
+    let specular = fresnel * ggx_d(saturate(dot(n, h)), alpha_sq) * smith_g1(n_dot_v, alpha_sq)
+        * smith_g1(n_dot_l, alpha_sq) / (4.0 * n_dot_v * n_dot_l);
+    let diffuse = (1.0 - fresnel) * material.albedo * (1.0 - material.metallic) / PI;
+
+    return (diffuse + specular) * n_dot_l;
+}
+
+// The part of `eval_bsdf` reflected by the diffuse lobe, per channel
+fn diffuse_fraction(material: Material, n: vec3<f32>, wo: vec3<f32>, wi: vec3<f32>, eta: f32) -> vec3<f32> {
+    let bsdf = eval_bsdf(material, n, wo, wi, eta);
+    let diffuse = eval_opaque_diffuse(material, n, wo, wi) * (1.0 - transmission_weight(material));
+
+    return select(vec3<f32>(), diffuse / bsdf, bsdf > vec3<f32>());
+}
+
+// The diffuse lobe of `eval_opaque`
+fn eval_opaque_diffuse(material: Material, n: vec3<f32>, wo: vec3<f32>, wi: vec3<f32>) -> vec3<f32> {
+    let n_dot_v = dot(n, wo);
+    let n_dot_l = dot(n, wi);
+    if n_dot_v <= 0.0 || n_dot_l <= 0.0 {
+        return vec3<f32>();
+    }
+
+    let fresnel = fresnel_schlick(specular_f0(material), dot(wo, normalize(wo + wi)));
+    return (1.0 - fresnel) * material.albedo * (1.0 - material.metallic) / PI * n_dot_l;
+}
+
+fn opaque_pdf(material: Material, n: vec3<f32>, wo: vec3<f32>, wi: vec3<f32>) -> f32 {
+    let n_dot_v = dot(n, wo);
+    let n_dot_l = dot(n, wi);
+    if n_dot_v <= 0.0 || n_dot_l <= 0.0 {
+        return 0.0;
+    }
+
+    let h = normalize(wo + wi);
+    let alpha = roughness_to_alpha(material.roughness);
+    let alpha_sq = alpha * alpha;
+
+    let specular_pdf = smith_g1(n_dot_v, alpha_sq) * ggx_d(saturate(dot(n, h)), alpha_sq) / (4.0 * n_dot_v);
+    let diffuse_pdf = n_dot_l / PI;
+
+    return mix(diffuse_pdf, specular_pdf, specular_probability(material, n_dot_v));
+}
+
+fn sample_opaque(material: Material, n: vec3<f32>, wo: vec3<f32>, state: ptr<function, u32>) -> vec3<f32> {
+    if pcg_random(state) < specular_probability(material, dot(n, wo)) {
+        let frame = orthonormal_basis(n);
+        let alpha = roughness_to_alpha(material.roughness);
+        let h = sample_ggx_vndf(wo * frame, alpha, vec2<f32>(pcg_random(state), pcg_random(state)));
+        return frame * reflect(-(wo * frame), h);
+    }
+
+    return normalize(n + random_direction(state)); // Lambertian distribution
+}
+
+// Returns the half vector of a refraction from `wo` to `wi`, facing the same side as `n`
+fn refraction_half_vector(n: vec3<f32>, wo: vec3<f32>, wi: vec3<f32>, eta: f32) -> vec3<f32> {
+    let h = normalize(wi * eta + wo);
+    return select(-h, h, dot(h, n) >= 0.0);
+}
+
+// Rough dielectric reflection and refraction (Walter et al. 2007)
+fn eval_dielectric(material: Material, n: vec3<f32>, wo: vec3<f32>, wi: vec3<f32>, eta: f32) -> vec3<f32> {
+    let n_dot_v = dot(n, wo);
+    let n_dot_l = dot(n, wi);
+    if n_dot_v <= 0.0 || n_dot_l == 0.0 {
+        return vec3<f32>();
+    }
+
+    let alpha = roughness_to_alpha(material.roughness);
+    let alpha_sq = alpha * alpha;
+
+    if n_dot_l > 0.0 {
+        let h = normalize(wo + wi);
+        let fresnel = fresnel_dielectric(dot(wo, h), eta);
+        let reflection = fresnel * ggx_d(saturate(dot(n, h)), alpha_sq) * smith_g1(n_dot_v, alpha_sq)
+            * smith_g1(n_dot_l, alpha_sq) / (4.0 * n_dot_v);
+
+        return vec3<f32>(reflection);
+    }
+
+    let h = refraction_half_vector(n, wo, wi, eta);
+    let wo_dot_h = dot(wo, h);
+    let wi_dot_h = dot(wi, h);
+    // Refraction needs `wo` and `wi` on opposite sides of the microfacet
+    if wo_dot_h <= 0.0 || wi_dot_h >= 0.0 {
+        return vec3<f32>();
+    }
+
+    let fresnel = fresnel_dielectric(wo_dot_h, eta);
+    let denom = wi_dot_h + wo_dot_h / eta;
+    // Radiance is compressed into a smaller solid angle when entering a denser medium, hence `eta^2`
+    let refraction = (1.0 - fresnel) * ggx_d(saturate(dot(n, h)), alpha_sq) * smith_g1(n_dot_v, alpha_sq)
+        * smith_g1(-n_dot_l, alpha_sq) * abs(wi_dot_h * wo_dot_h / (denom * denom * n_dot_v)) / (eta * eta);
+
+    return material.transmission_tint * refraction;
+}
+
+fn dielectric_pdf(material: Material, n: vec3<f32>, wo: vec3<f32>, wi: vec3<f32>, eta: f32) -> f32 {
+    let n_dot_v = dot(n, wo);
+    let n_dot_l = dot(n, wi);
+    if n_dot_v <= 0.0 || n_dot_l == 0.0 {
+        return 0.0;
+    }
+
+    let alpha = roughness_to_alpha(material.roughness);
+    let alpha_sq = alpha * alpha;
+
+    if n_dot_l > 0.0 {
+        let h = normalize(wo + wi);
+        let fresnel = fresnel_dielectric(dot(wo, h), eta);
+
+        return fresnel * smith_g1(n_dot_v, alpha_sq) * ggx_d(saturate(dot(n, h)), alpha_sq) / (4.0 * n_dot_v);
+    }
+
+    let h = refraction_half_vector(n, wo, wi, eta);
+    let wo_dot_h = dot(wo, h);
+    let wi_dot_h = dot(wi, h);
+    if wo_dot_h <= 0.0 || wi_dot_h >= 0.0 {
+        return 0.0;
+    }
+
+    let fresnel = fresnel_dielectric(wo_dot_h, eta);
+    let denom = wi_dot_h + wo_dot_h / eta;
+    let visible_normal_pdf = smith_g1(n_dot_v, alpha_sq) * ggx_d(saturate(dot(n, h)), alpha_sq) * wo_dot_h / n_dot_v;
+
+    return (1.0 - fresnel) * visible_normal_pdf * abs(wi_dot_h) / (denom * denom);
+}
+
+// Picks reflection or refraction off a visible microfacet based on its Fresnel reflectance
+fn sample_dielectric(material: Material, n: vec3<f32>, wo: vec3<f32>, eta: f32, state: ptr<function, u32>) -> vec3<f32> {
+    let frame = orthonormal_basis(n);
+    let alpha = roughness_to_alpha(material.roughness);
+    let h = frame * sample_ggx_vndf(wo * frame, alpha, vec2<f32>(pcg_random(state), pcg_random(state)));
+
+    let fresnel = fresnel_dielectric(dot(wo, h), eta);
+    let refracted = refract(-wo, h, 1.0 / eta);
+    // `refract()` returns a zero vector on total internal reflection
+    if pcg_random(state) < fresnel || all(refracted == vec3<f32>()) {
+        return reflect(-wo, h);
+    }
+
+    return normalize(refracted);
+}
+
+fn pcg_hash(input: u32) -> u32 {
+    let state = input * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+
+    return (word >> 22u) ^ word;
+}
+
 fn pcg_random(state: ptr<function, u32>) -> f32 {
     *state = *state * 747796405u + 2891336453u;
 
@@ -0,0 +1,344 @@
+use std::{
+    f32::consts::{FRAC_PI_2, PI},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use glam::{Quat, Vec3};
+
+use crate::{
+    light::{Light, LightShape},
+    transform::Transform,
+};
+
+/// Converts luminance in cd/m² to the radiance units of the renderer, so a clear midday sky is
+/// about as bright as the default sky color
+const LUMINANCE_SCALE: f32 = 1.0e-4;
+/// Illuminance of the sun at the top of the atmosphere in lux
+const SOLAR_ILLUMINANCE: f32 = 128_000.0;
+/// Wavelengths in micrometers used for the red, green and blue sun transmittance
+const WAVELENGTHS: Vec3 = Vec3::new(0.65, 0.57, 0.475);
+
+/// An analytic daylight sky (Preetham et al. 1999) with a matching directional sun
+///
+/// North is -Z, east is +X and up is +Y. Directions below the horizon see a uniformly lit ground.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sky {
+    pub sun: SunPosition,
+    /// Haziness of the atmosphere, from 2 for a very clear sky to about 10 for a hazy one
+    pub turbidity: f32,
+    /// Reflectance of the ground below the horizon
+    pub ground_albedo: Vec3,
+    /// Multiplies the radiance of the sky and the sun
+    pub intensity: f32,
+    /// Half the apparent size of the sun in radians, softens its shadows
+    pub sun_angular_radius: f32,
+}
+
+/// Where the sun is in the sky
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SunPosition {
+    /// Angle above the horizon in radians
+    pub elevation: f32,
+    /// Compass direction in radians, clockwise from north (-Z) toward east (+X)
+    pub azimuth: f32,
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Self {
+            sun: SunPosition {
+                elevation: 45f32.to_radians(),
+                azimuth: 135f32.to_radians(),
+            },
+            turbidity: 3.0,
+            ground_albedo: Vec3::splat(0.3),
+            intensity: 1.0,
+            sun_angular_radius: 0.00465,
+        }
+    }
+}
+
+impl SunPosition {
+    /// Computes the position of the sun seen from a place on earth at a moment in time
+    ///
+    /// `latitude` and `longitude` are in degrees, north and east are positive. Accurate to about
+    /// a hundredth of a degree between 1950 and 2050.
+    #[allow(unused)]
+    pub fn from_datetime(latitude: f64, longitude: f64, time: SystemTime) -> SunPosition {
+        let seconds = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs_f64(),
+            Err(error) => -error.duration().as_secs_f64(),
+        };
+        // Days since noon of January 1st 2000 (J2000.0)
+        let n = seconds / 86400.0 - 10957.5;
+
+        // Ecliptic longitude of the sun and obliquity of the ecliptic
+        let mean_longitude = 280.460 + 0.9856474 * n;
+        let mean_anomaly = (357.528 + 0.9856003 * n).to_radians();
+        let ecliptic_longitude =
+            (mean_longitude + 1.915 * mean_anomaly.sin() + 0.020 * (2.0 * mean_anomaly).sin())
+                .to_radians();
+        let obliquity = (23.439 - 0.0000004 * n).to_radians();
+
+        // Equatorial coordinates
+        let right_ascension = (obliquity.cos() * ecliptic_longitude.sin())
+            .atan2(ecliptic_longitude.cos())
+            .to_degrees();
+        let declination = (obliquity.sin() * ecliptic_longitude.sin()).asin();
+
+        // Local hour angle from the Greenwich mean sidereal time
+        let sidereal_time = 280.46061837 + 360.98564736629 * n + longitude;
+        let hour_angle = (sidereal_time - right_ascension).to_radians();
+
+        let latitude = latitude.to_radians();
+        let elevation = (latitude.sin() * declination.sin()
+            + latitude.cos() * declination.cos() * hour_angle.cos())
+        .asin();
+        let azimuth = (-declination.cos() * hour_angle.sin()).atan2(
+            declination.sin() * latitude.cos()
+                - declination.cos() * hour_angle.cos() * latitude.sin(),
+        );
+
+        SunPosition {
+            elevation: elevation as f32,
+            azimuth: azimuth.rem_euclid(std::f64::consts::TAU) as f32,
+        }
+    }
+
+    /// The world space direction toward the sun
+    pub fn direction(&self) -> Vec3 {
+        let (sin_elevation, cos_elevation) = self.elevation.sin_cos();
+        let (sin_azimuth, cos_azimuth) = self.azimuth.sin_cos();
+
+        Vec3::new(
+            cos_elevation * sin_azimuth,
+            sin_elevation,
+            -cos_elevation * cos_azimuth,
+        )
+    }
+}
+
+impl Sky {
+    /// The directional light of the sun, or `None` if it's below the horizon
+    pub fn sun_light(&self) -> Option<Light> {
+        if self.sun.elevation <= -self.sun_angular_radius {
+            return None;
+        }
+
+        let irradiance = self.sun_irradiance();
+        let intensity = irradiance.max_element();
+        if intensity <= 0.0 {
+            return None;
+        }
+
+        Some(Light {
+            shape: LightShape::Directional {
+                angular_radius: self.sun_angular_radius,
+            },
+            color: irradiance / intensity,
+            intensity,
+            transform: Transform {
+                rotation: Quat::from_rotation_arc(Vec3::NEG_Z, -self.sun.direction()),
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Irradiance of the sun at normal incidence after the atmosphere scattered and absorbed
+    /// part of it
+    pub fn sun_irradiance(&self) -> Vec3 {
+        let zenith_angle = FRAC_PI_2 - self.sun.elevation.max(0.0);
+
+        // Relative optical air mass (Kasten 1966), also from the appendix of the Preetham paper
+        let air_mass =
+            1.0 / (zenith_angle.cos() + 0.15 * (93.885 - zenith_angle.to_degrees()).powf(-1.253));
+
+        // Rayleigh scattering by molecules and Ångström's formula for aerosols
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let rayleigh = (-0.008735 * WAVELENGTHS.powf(-4.08) * air_mass).exp();
+        let aerosol = (-beta * WAVELENGTHS.powf(-1.3) * air_mass).exp();
+
+        rayleigh * aerosol * SOLAR_ILLUMINANCE * LUMINANCE_SCALE * self.intensity
+    }
+
+    /// The Perez distribution coefficients A to E, with the x and y chromaticity and the
+    /// luminance Y in the components of each
+    pub fn perez_coefficients(&self) -> [Vec3; 5] {
+        let t = self.turbidity;
+
+        [
+            Vec3::new(
+                -0.0193 * t - 0.2592,
+                -0.0167 * t - 0.2608,
+                0.1787 * t - 1.4630,
+            ),
+            Vec3::new(
+                -0.0665 * t + 0.0008,
+                -0.0950 * t + 0.0092,
+                -0.3554 * t + 0.4275,
+            ),
+            Vec3::new(
+                -0.0004 * t + 0.2125,
+                -0.0079 * t + 0.2102,
+                -0.0227 * t + 5.3251,
+            ),
+            Vec3::new(
+                -0.0641 * t - 0.8989,
+                -0.0441 * t - 1.6537,
+                0.1206 * t - 2.5771,
+            ),
+            Vec3::new(
+                -0.0033 * t + 0.0452,
+                -0.0109 * t + 0.0529,
+                -0.0670 * t + 0.3703,
+            ),
+        ]
+    }
+
+    /// The xyY color of the zenith divided by the Perez distribution at the zenith, so the
+    /// distribution in any direction times this value is that direction's color
+    pub fn zenith_scale(&self) -> Vec3 {
+        let t = self.turbidity;
+        // The model only covers a sun above the horizon
+        let theta = FRAC_PI_2 - self.sun.elevation.max(0.0);
+        let theta_2 = theta * theta;
+        let theta_3 = theta_2 * theta;
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+        let luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+
+        let x = t * t * (0.00166 * theta_3 - 0.00375 * theta_2 + 0.00209 * theta)
+            + t * (-0.02903 * theta_3 + 0.06377 * theta_2 - 0.03202 * theta + 0.00394)
+            + (0.11693 * theta_3 - 0.21196 * theta_2 + 0.06052 * theta + 0.25886);
+        let y = t * t * (0.00275 * theta_3 - 0.00610 * theta_2 + 0.00317 * theta)
+            + t * (-0.04214 * theta_3 + 0.08970 * theta_2 - 0.04153 * theta + 0.00516)
+            + (0.15346 * theta_3 - 0.26756 * theta_2 + 0.06670 * theta + 0.26688);
+
+        // The zenith luminance is in kcd/m²
+        let zenith = Vec3::new(x, y, luminance * 1000.0 * LUMINANCE_SCALE * self.intensity);
+        zenith / perez(self.perez_coefficients(), 1.0, theta)
+    }
+
+    /// Radiance of the sky without the sun in a normalized direction, matching the shader
+    #[allow(unused)]
+    pub fn radiance(&self, direction: Vec3) -> Vec3 {
+        if direction.y <= 0.0 {
+            return self.ground_radiance();
+        }
+
+        let cos_gamma = direction.dot(self.sun_model_direction()).clamp(-1.0, 1.0);
+        let x_y_luminance =
+            self.zenith_scale() * perez(self.perez_coefficients(), direction.y, cos_gamma.acos());
+
+        x_y_luminance_to_rgb(x_y_luminance)
+    }
+
+    /// Radiance of the diffuse ground lit by the sun and the sky
+    pub fn ground_radiance(&self) -> Vec3 {
+        let sun = self.sun_irradiance() * self.sun.elevation.max(0.0).sin();
+
+        // Integrates the sky over the upper hemisphere with the midpoint rule
+        const THETA_STEPS: usize = 16;
+        const PHI_STEPS: usize = 32;
+        let coefficients = self.perez_coefficients();
+        let zenith_scale = self.zenith_scale();
+        let sun_direction = self.sun_model_direction();
+        let mut sky = Vec3::ZERO;
+        for i in 0..THETA_STEPS {
+            let theta = (i as f32 + 0.5) / THETA_STEPS as f32 * FRAC_PI_2;
+            let (sin_theta, cos_theta) = theta.sin_cos();
+            // cos(theta) * d_omega
+            let weight = cos_theta
+                * sin_theta
+                * (FRAC_PI_2 / THETA_STEPS as f32)
+                * (2.0 * PI / PHI_STEPS as f32);
+
+            for j in 0..PHI_STEPS {
+                let phi = (j as f32 + 0.5) / PHI_STEPS as f32 * 2.0 * PI;
+                let direction = Vec3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin());
+                let gamma = direction.dot(sun_direction).clamp(-1.0, 1.0).acos();
+                let x_y_luminance = zenith_scale * perez(coefficients, cos_theta, gamma);
+
+                sky += x_y_luminance_to_rgb(x_y_luminance) * weight;
+            }
+        }
+
+        self.ground_albedo * (sun + sky) / PI
+    }
+
+    /// The sun direction the sky model is evaluated with, which stays above the horizon
+    pub fn sun_model_direction(&self) -> Vec3 {
+        SunPosition {
+            elevation: self.sun.elevation.max(0.0),
+            ..self.sun
+        }
+        .direction()
+    }
+}
+
+/// The Perez sky distribution for a direction at the zenith angle `acos(cos_theta)` and the angle
+/// `gamma` from the sun
+fn perez(coefficients: [Vec3; 5], cos_theta: f32, gamma: f32) -> Vec3 {
+    let [a, b, c, d, e] = coefficients;
+    let cos_gamma = gamma.cos();
+
+    (Vec3::ONE + a * (b / cos_theta.max(0.001)).exp())
+        * (Vec3::ONE + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+/// Converts a CIE xyY color to linear sRGB
+fn x_y_luminance_to_rgb(color: Vec3) -> Vec3 {
+    let Vec3 { x, y, z: luminance } = color;
+    if y <= 0.0 {
+        return Vec3::ZERO;
+    }
+
+    let xyz = Vec3::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+    let rgb = Vec3::new(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    );
+
+    rgb.max(Vec3::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn sun_position_matches_the_spa_reference() {
+        // The example of NREL's Solar Position Algorithm (Reda and Andreas 2004): Golden,
+        // Colorado on 2003-10-17 at 12:30:30 local time (19:30:30 UTC). SPA gives a topocentric
+        // zenith of 50.11162° and an azimuth of 194.34024°. The zenith includes 0.0163° of
+        // refraction and 0.0019° of parallax, which aren't modeled here.
+        let time = UNIX_EPOCH + Duration::from_secs(1_066_419_030);
+        let sun = SunPosition::from_datetime(39.742476, -105.1786, time);
+
+        let elevation = sun.elevation.to_degrees();
+        let azimuth = sun.azimuth.to_degrees();
+        assert!(
+            (elevation - (90.0 - 50.11162 - 0.0163 + 0.0019)).abs() < 0.01,
+            "{elevation}"
+        );
+        assert!((azimuth - 194.34024).abs() < 0.01, "{azimuth}");
+    }
+
+    #[test]
+    fn direction_follows_the_compass() {
+        let east = SunPosition {
+            elevation: 0.0,
+            azimuth: FRAC_PI_2,
+        };
+        assert!(east.direction().abs_diff_eq(Vec3::X, 1e-6));
+
+        let zenith = SunPosition {
+            elevation: FRAC_PI_2,
+            azimuth: 1.0,
+        };
+        assert!(zenith.direction().abs_diff_eq(Vec3::Y, 1e-6));
+    }
+}
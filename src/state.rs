@@ -1,87 +1,50 @@
 use std::sync::Arc;
 
-use glam::Mat4;
+use wgpu::util::DeviceExt;
 use winit::window::Window;
 
-use crate::scene::Scene;
+use crate::{
+    render_settings::RenderSettings,
+    renderer::Renderer,
+    scene::Scene,
+    shader_types::GpuToneMapping,
+    tone_mapping::{ToneMapOperator, ToneMapping},
+};
 
 pub struct State {
     window: Arc<Window>,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
     size: winit::dpi::PhysicalSize<u32>,
     surface: wgpu::Surface<'static>,
     surface_format: wgpu::TextureFormat,
 
-    rt_target: wgpu::Texture,
-    #[expect(dead_code)]
-    rt_view: wgpu::TextureView,
-    compute_pipeline: wgpu::ComputePipeline,
-    compute_bind_group: wgpu::BindGroup,
-    tlas_package: wgpu::TlasPackage,
+    renderer: Renderer,
     blit_pipeline: wgpu::RenderPipeline,
+    blit_sampler: wgpu::Sampler,
+    tone_mapping_buffer: wgpu::Buffer,
     blit_bind_group: wgpu::BindGroup,
-    scene: Scene,
+    /// The render target revision of the renderer `blit_bind_group` was created with
+    render_target_revision: u64,
 }
 
 impl State {
-    pub async fn new(window: Arc<Window>, mut scene: Scene) -> State {
+    pub async fn new(window: Arc<Window>, scene: Scene) -> State {
         let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+
+        let size = window.inner_size();
+
+        let surface = instance.create_surface(window.clone()).unwrap();
         let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions::default())
-            .await
-            .unwrap();
-        let (device, queue) = adapter
-            .request_device(&wgpu::DeviceDescriptor {
-                required_features: wgpu::Features::TEXTURE_BINDING_ARRAY
-                    | wgpu::Features::VERTEX_WRITABLE_STORAGE
-                    | wgpu::Features::EXPERIMENTAL_RAY_QUERY
-                    | wgpu::Features::EXPERIMENTAL_RAY_TRACING_ACCELERATION_STRUCTURE,
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                compatible_surface: Some(&surface),
                 ..Default::default()
             })
             .await
             .unwrap();
-
-        let size = window.inner_size();
-
-        let surface = instance.create_surface(window.clone()).unwrap();
         let cap = surface.get_capabilities(&adapter);
         let surface_format = cap.formats[0];
 
-        let side_count = 8;
-        let tlas = device.create_tlas(&wgpu::CreateTlasDescriptor {
-            label: None,
-            flags: wgpu::AccelerationStructureFlags::PREFER_FAST_TRACE,
-            update_mode: wgpu::AccelerationStructureUpdateMode::Build,
-            max_instances: side_count * side_count,
-        });
-
-        let rt_target = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("rt_target"),
-            size: wgpu::Extent3d {
-                width: size.width,
-                height: size.height,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8Unorm,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
-            view_formats: &[wgpu::TextureFormat::Rgba8Unorm],
-        });
-
-        let rt_view = rt_target.create_view(&wgpu::TextureViewDescriptor {
-            label: None,
-            format: Some(wgpu::TextureFormat::Rgba8Unorm),
-            dimension: Some(wgpu::TextureViewDimension::D2),
-            usage: None,
-            aspect: wgpu::TextureAspect::All,
-            base_mip_level: 0,
-            mip_level_count: None,
-            base_array_layer: 0,
-            array_layer_count: None,
-        });
+        let renderer = Renderer::new(&adapter, size, scene).await;
+        let device = renderer.device();
 
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             label: Some("rt_sampler"),
@@ -94,58 +57,6 @@ impl State {
             ..Default::default()
         });
 
-        let rt_compute_shader =
-            device.create_shader_module(wgpu::include_wgsl!("shaders/rt_compute.wgsl"));
-
-        let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("rt"),
-            layout: None,
-            module: &rt_compute_shader,
-            entry_point: Some("main"),
-            compilation_options: Default::default(),
-            cache: None,
-        });
-
-        let compute_bind_group_layout = compute_pipeline.get_bind_group_layout(0);
-        let tlas_package = wgpu::TlasPackage::new(tlas);
-
-        let gpu_scene = scene.get_or_upload_gpu_scene(&device, &queue, size);
-
-        let compute_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &compute_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&rt_view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: gpu_scene.uniform_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: gpu_scene.vertex_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: gpu_scene.index_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 4,
-                    resource: gpu_scene.material_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 5,
-                    resource: gpu_scene.instance_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 6,
-                    resource: tlas_package.as_binding(),
-                },
-            ],
-        });
-
         let blit_shader = device.create_shader_module(wgpu::include_wgsl!("shaders/blit.wgsl"));
 
         let blit_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
@@ -173,38 +84,26 @@ impl State {
             cache: None,
         });
 
-        let blit_bind_group_layout = blit_pipeline.get_bind_group_layout(0);
-
-        let blit_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &blit_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&rt_view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&sampler),
-                },
-            ],
+        let tone_mapping_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("tone_mapping_buffer"),
+            contents: bytemuck::bytes_of(&GpuToneMapping::from(&renderer.settings().tone_mapping)),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
+        let blit_bind_group =
+            create_blit_bind_group(&renderer, &blit_pipeline, &sampler, &tone_mapping_buffer);
+
         let state = State {
             window,
-            device,
-            queue,
             size,
             surface,
             surface_format,
-            rt_target,
-            rt_view,
-            compute_pipeline,
-            compute_bind_group,
-            tlas_package,
+            render_target_revision: renderer.render_target_revision(),
+            renderer,
             blit_pipeline,
+            blit_sampler: sampler,
+            tone_mapping_buffer,
             blit_bind_group,
-            scene,
         };
 
         state.configure_surface();
@@ -223,15 +122,20 @@ impl State {
             desired_maximum_frame_latency: 2,
             present_mode: wgpu::PresentMode::AutoVsync,
         };
-        self.surface.configure(&self.device, &surface_config);
+        self.surface
+            .configure(self.renderer.device(), &surface_config);
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        // Minimized windows report a zero size, which surfaces can't be configured with
+        if new_size.width == 0 || new_size.height == 0 {
+            return;
+        }
         self.size = new_size;
 
         self.configure_surface();
 
-        self.scene.update_camera_size(&self.queue, self.size);
+        self.renderer.resize(self.size);
     }
 
     pub fn render(&mut self) {
@@ -246,52 +150,39 @@ impl State {
                 ..Default::default()
             });
 
-        // Keep in `render()` for transform change support in the future
-        let gpu_scene = self
-            .scene
-            .get_or_upload_gpu_scene(&self.device, &self.queue, self.size);
-        let mut tlas_i = 0usize;
-        for (instance_i, (blas, transforms)) in gpu_scene
-            .bottom_level_acceleration_structures
-            .iter()
-            .zip(gpu_scene.instance_transforms.iter())
-            .enumerate()
-        {
-            for transform in transforms {
-                self.tlas_package[tlas_i] = Some(wgpu::TlasInstance::new(
-                    blas,
-                    Mat4::from(transform).transpose().to_cols_array()[..12]
-                        .try_into()
-                        .unwrap(),
-                    instance_i as u32,
-                    0xff,
-                ));
-
-                tlas_i += 1;
-            }
-        }
-
         let mut encoder = self
-            .device
+            .renderer
+            .device()
             .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
-        encoder
-            .build_acceleration_structures(std::iter::empty(), std::iter::once(&self.tlas_package));
 
-        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-            label: None,
-            timestamp_writes: None,
-        });
+        self.renderer.encode(&mut encoder);
+
+        // Resizes and resolution scale changes replace the image that is blitted
+        if self.renderer.render_target_revision() != self.render_target_revision {
+            self.render_target_revision = self.renderer.render_target_revision();
+            self.blit_bind_group = create_blit_bind_group(
+                &self.renderer,
+                &self.blit_pipeline,
+                &self.blit_sampler,
+                &self.tone_mapping_buffer,
+            );
+        }
 
-        compute_pass.set_pipeline(&self.compute_pipeline);
-        compute_pass.set_bind_group(0, Some(&self.compute_bind_group), &[]);
-        compute_pass.dispatch_workgroups(
-            self.rt_target.width() / 8,
-            self.rt_target.height() / 8,
-            1,
+        // AOVs other than the lighting passes are shown as they are
+        let settings = self.renderer.settings();
+        let tone_mapping = match settings.view_aov {
+            Some(aov) if !aov.is_radiance() => ToneMapping {
+                operator: ToneMapOperator::Clamp,
+                ..Default::default()
+            },
+            _ => settings.tone_mapping,
+        };
+        self.renderer.queue().write_buffer(
+            &self.tone_mapping_buffer,
+            0,
+            bytemuck::bytes_of(&GpuToneMapping::from(&tone_mapping)),
         );
 
-        drop(compute_pass);
-
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: None,
             color_attachments: &[Some(wgpu::RenderPassColorAttachment {
@@ -313,12 +204,58 @@ impl State {
 
         drop(render_pass);
 
-        self.queue.submit([encoder.finish()]);
+        self.renderer.queue().submit([encoder.finish()]);
         self.window.pre_present_notify();
         surface_texture.present();
     }
 
+    pub fn settings(&self) -> &RenderSettings {
+        self.renderer.settings()
+    }
+
+    pub fn set_settings(&mut self, settings: RenderSettings) {
+        self.renderer.set_settings(settings);
+    }
+
+    pub fn scene(&self) -> &Scene {
+        self.renderer.scene()
+    }
+
+    /// Returns the scene for editing, accumulation restarts once a change is detected
+    pub fn scene_mut(&mut self) -> &mut Scene {
+        self.renderer.scene_mut()
+    }
+
     pub fn get_window(&self) -> &Window {
         &self.window
     }
 }
+
+/// Binds the raytraced image of `renderer` and the tone mapping for the blit pass
+fn create_blit_bind_group(
+    renderer: &Renderer,
+    blit_pipeline: &wgpu::RenderPipeline,
+    sampler: &wgpu::Sampler,
+    tone_mapping_buffer: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    renderer
+        .device()
+        .create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &blit_pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(renderer.rt_view()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: tone_mapping_buffer.as_entire_binding(),
+                },
+            ],
+        })
+}
@@ -0,0 +1,44 @@
+use std::path::Path;
+
+/// How the values of a texture are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    /// sRGB encoded colors that are converted to linear when sampled, used for albedo and emissive
+    Srgb,
+    /// Values that are used as is, used for data like roughness and normal maps
+    Linear,
+}
+
+/// An RGBA8 image that materials can sample
+#[derive(Debug, Clone)]
+pub struct Texture {
+    pub width: u32,
+    pub height: u32,
+    /// Tightly packed RGBA8 pixels, row by row starting at the top
+    pub pixels: Vec<u8>,
+    pub color_space: ColorSpace,
+}
+
+impl Texture {
+    /// Loads a PNG or JPEG image
+    pub fn load(
+        path: impl AsRef<Path>,
+        color_space: ColorSpace,
+    ) -> Result<Texture, image::ImageError> {
+        let image = image::open(path)?.into_rgba8();
+
+        Ok(Texture {
+            width: image.width(),
+            height: image.height(),
+            pixels: image.into_raw(),
+            color_space,
+        })
+    }
+
+    pub fn format(&self) -> wgpu::TextureFormat {
+        match self.color_space {
+            ColorSpace::Srgb => wgpu::TextureFormat::Rgba8UnormSrgb,
+            ColorSpace::Linear => wgpu::TextureFormat::Rgba8Unorm,
+        }
+    }
+}
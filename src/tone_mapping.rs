@@ -0,0 +1,255 @@
+use glam::{Mat3, Vec3};
+
+/// Turns linear radiance into colors a display can show, in the blit pass and when saving
+/// low dynamic range images
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMapping {
+    pub operator: ToneMapOperator,
+    /// Exposure compensation in stops, every stop doubles the brightness
+    pub exposure: f32,
+    /// The color temperature in kelvin of the light that should look white, lower values make
+    /// the image bluer
+    pub temperature: f32,
+    /// Green-magenta correction, positive values make the image more magenta
+    pub tint: f32,
+}
+
+/// The curve that compresses high dynamic range colors into the displayable range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapOperator {
+    /// Clips everything above 1
+    Clamp,
+    /// `x / (1 + x)` per channel
+    Reinhard,
+    /// Stephen Hill's fit of the ACES reference rendering and output transforms
+    AcesFilmic,
+    /// Troy Sobotka's AgX with Benjamin Wrensch's polynomial fit of the default contrast
+    Agx,
+    /// The Khronos PBR Neutral tone mapper, which keeps base colors intact below a threshold
+    KhronosPbrNeutral,
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self {
+            operator: ToneMapOperator::KhronosPbrNeutral,
+            exposure: 0.0,
+            temperature: 6500.0,
+            tint: 0.0,
+        }
+    }
+}
+
+impl ToneMapOperator {
+    /// Matches the operator indices of `blit.wgsl`
+    pub fn index(self) -> u32 {
+        match self {
+            ToneMapOperator::Clamp => 0,
+            ToneMapOperator::Reinhard => 1,
+            ToneMapOperator::AcesFilmic => 2,
+            ToneMapOperator::Agx => 3,
+            ToneMapOperator::KhronosPbrNeutral => 4,
+        }
+    }
+}
+
+impl ToneMapping {
+    /// Linear sRGB to linear sRGB matrix that makes light of `temperature` and `tint` white,
+    /// through a Bradford chromatic adaptation to 6500 K
+    pub fn white_balance(&self) -> Mat3 {
+        let source = white_point(self.temperature, self.tint);
+        let destination = white_point(6500.0, 0.0);
+
+        // The matrices are written row by row
+        let bradford = Mat3::from_cols_array(&[
+            0.8951, 0.2664, -0.1614, //
+            -0.7502, 1.7135, 0.0367, //
+            0.0389, -0.0685, 1.0296,
+        ])
+        .transpose();
+        let srgb_to_xyz = Mat3::from_cols_array(&[
+            0.4124564, 0.3575761, 0.1804375, //
+            0.2126729, 0.7151522, 0.0721750, //
+            0.0193339, 0.119192, 0.9503041,
+        ])
+        .transpose();
+
+        let scale = Mat3::from_diagonal((bradford * destination) / (bradford * source));
+
+        srgb_to_xyz.inverse() * bradford.inverse() * scale * bradford * srgb_to_xyz
+    }
+
+    /// Applies exposure, white balance and the operator to a linear color, matching `blit.wgsl`
+    ///
+    /// The result is linear and between 0 and 1.
+    pub fn apply(&self, color: Vec3) -> Vec3 {
+        let color = (self.white_balance() * color * self.exposure.exp2()).max(Vec3::ZERO);
+
+        let mapped = match self.operator {
+            ToneMapOperator::Clamp => color,
+            ToneMapOperator::Reinhard => color / (Vec3::ONE + color),
+            ToneMapOperator::AcesFilmic => aces_filmic(color),
+            ToneMapOperator::Agx => agx(color),
+            ToneMapOperator::KhronosPbrNeutral => khronos_pbr_neutral(color),
+        };
+
+        mapped.clamp(Vec3::ZERO, Vec3::ONE)
+    }
+}
+
+/// The XYZ color with a luminance of 1 of a Planckian light, with `tint` moving it along y
+fn white_point(temperature: f32, tint: f32) -> Vec3 {
+    // Kim et al.'s cubic spline approximation of the Planckian locus
+    let t = temperature.clamp(1667.0, 25000.0);
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / (t * t * t) - 0.2343589e6 / (t * t) + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.025847e9 / (t * t * t) + 2.107038e6 / (t * t) + 0.2226347e3 / t + 0.240390
+    };
+    let y = if t <= 2222.0 {
+        -1.1063814 * x * x * x - 1.3481102 * x * x + 2.1855583 * x - 0.20219684
+    } else if t <= 4000.0 {
+        -0.9549476 * x * x * x - 1.3741859 * x * x + 2.09137 * x - 0.16748866
+    } else {
+        3.081758 * x * x * x - 5.873387 * x * x + 3.7511299 * x - 0.37001482
+    } + tint * 0.02;
+
+    Vec3::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+fn aces_filmic(color: Vec3) -> Vec3 {
+    let input = Mat3::from_cols_array(&[
+        0.59719, 0.35458, 0.04823, //
+        0.07600, 0.90834, 0.01566, //
+        0.02840, 0.13383, 0.83777,
+    ])
+    .transpose();
+    let output = Mat3::from_cols_array(&[
+        1.60475, -0.53108, -0.07367, //
+        -0.10208, 1.10813, -0.00605, //
+        -0.00327, -0.07276, 1.07602,
+    ])
+    .transpose();
+
+    let v = input * color;
+    let a = v * (v + 0.0245786) - 9.0537e-05;
+    let b = v * (0.983729 * v + 0.432951) + 0.238081;
+
+    output * (a / b)
+}
+
+fn agx(color: Vec3) -> Vec3 {
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    let inset = Mat3::from_cols_array(&[
+        0.84247905,
+        0.042328242,
+        0.042375654,
+        0.0784336,
+        0.87846863,
+        0.0784336,
+        0.079223745,
+        0.07916613,
+        0.879143,
+    ]);
+    let outset = Mat3::from_cols_array(&[
+        1.196879,
+        -0.052896854,
+        -0.052971635,
+        -0.09802088,
+        1.1519032,
+        -0.09804345,
+        -0.09902974,
+        -0.098961174,
+        1.1510737,
+    ]);
+
+    let log = (inset * color).max(Vec3::splat(1e-10)).map(f32::log2);
+    let x = (log.clamp(Vec3::splat(MIN_EV), Vec3::splat(MAX_EV)) - MIN_EV) / (MAX_EV - MIN_EV);
+
+    // Polynomial fit of the default AgX contrast curve
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    let curve =
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232;
+
+    // The curve produces display encoded values, the 2.2 power makes them linear again
+    (outset * curve).max(Vec3::ZERO).powf(2.2)
+}
+
+fn khronos_pbr_neutral(color: Vec3) -> Vec3 {
+    const START_COMPRESSION: f32 = 0.8 - 0.04;
+    const DESATURATION: f32 = 0.15;
+
+    let x = color.min_element();
+    let offset = if x < 0.08 { x - 6.25 * x * x } else { 0.04 };
+    let color = color - offset;
+
+    let peak = color.max_element();
+    if peak < START_COMPRESSION {
+        return color;
+    }
+
+    let d = 1.0 - START_COMPRESSION;
+    let new_peak = 1.0 - d * d / (peak + d - START_COMPRESSION);
+    let color = color * (new_peak / peak);
+
+    let g = 1.0 - 1.0 / (DESATURATION * (peak - new_peak) + 1.0);
+    color.lerp(Vec3::splat(new_peak), g)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATORS: [ToneMapOperator; 5] = [
+        ToneMapOperator::Clamp,
+        ToneMapOperator::Reinhard,
+        ToneMapOperator::AcesFilmic,
+        ToneMapOperator::Agx,
+        ToneMapOperator::KhronosPbrNeutral,
+    ];
+
+    #[test]
+    fn white_balance_of_the_reference_white_is_identity() {
+        let white_balance = ToneMapping::default().white_balance();
+        assert!(
+            white_balance.abs_diff_eq(Mat3::IDENTITY, 1e-5),
+            "{white_balance}"
+        );
+    }
+
+    #[test]
+    fn warm_white_balance_cools_the_image() {
+        let tone_mapping = ToneMapping {
+            temperature: 3000.0,
+            ..Default::default()
+        };
+        let balanced = tone_mapping.white_balance() * Vec3::ONE;
+        assert!(balanced.z > balanced.x, "{balanced}");
+    }
+
+    #[test]
+    fn operators_keep_black_and_stay_in_range() {
+        for operator in OPERATORS {
+            let tone_mapping = ToneMapping {
+                operator,
+                ..Default::default()
+            };
+            let black = tone_mapping.apply(Vec3::ZERO);
+            assert!(black.abs_diff_eq(Vec3::ZERO, 1e-3), "{operator:?}: {black}");
+
+            for value in [0.01, 0.18, 0.5, 1.0, 4.0, 100.0, 1e6] {
+                for color in [Vec3::splat(value), Vec3::new(value, value * 0.1, 0.0)] {
+                    let mapped = tone_mapping.apply(color);
+                    assert!(
+                        mapped.cmpge(Vec3::ZERO).all() && mapped.cmple(Vec3::ONE).all(),
+                        "{operator:?}: {color} -> {mapped}"
+                    );
+                }
+            }
+        }
+    }
+}
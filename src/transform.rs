@@ -1,6 +1,6 @@
 use glam::{Mat4, Quat, Vec3};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Transform {
     pub translation: Vec3,
     pub scale: Vec3,
@@ -28,3 +28,15 @@ impl From<&Transform> for Mat4 {
         Self::from_scale_rotation_translation(value.scale, value.rotation, value.translation)
     }
 }
+
+impl From<Mat4> for Transform {
+    fn from(value: Mat4) -> Self {
+        let (scale, rotation, translation) = value.to_scale_rotation_translation();
+
+        Self {
+            translation,
+            scale,
+            rotation,
+        }
+    }
+}